@@ -0,0 +1,465 @@
+use axum::{
+    body::Body,
+    http::{header, Request, Response},
+};
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    io::Write as _,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_appender::non_blocking::NonBlocking;
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::{attempt, client_ip, labels, logging};
+
+/// How access log lines are written.
+#[derive(Clone, Default)]
+pub enum AccessFormat {
+    /// `key=value` pairs after a timestamp
+    #[default]
+    Text,
+    /// one JSON object per line
+    Json,
+    /// the Common Log Format of web servers
+    Common,
+    /// the Common Log Format followed by referer and user agent
+    Combined,
+    /// a line with `$variable`s filled in
+    Template(Vec<Piece>),
+}
+
+#[derive(Clone)]
+pub enum Piece {
+    Literal(String),
+    Variable(Variable),
+}
+
+#[derive(Clone, Copy)]
+pub enum Variable {
+    Time,
+    Peer,
+    Client,
+    Method,
+    Requested,
+    Path,
+    Version,
+    Status,
+    Rule,
+    Owner,
+    Tags,
+    Target,
+    UpstreamTime,
+    Bytes,
+    Referer,
+    UserAgent,
+    Attempts,
+    Environment,
+    Instance,
+}
+
+const VARIABLES: [(&str, Variable); 19] = [
+    ("time", Variable::Time),
+    ("peer", Variable::Peer),
+    ("client", Variable::Client),
+    ("method", Variable::Method),
+    ("requested", Variable::Requested),
+    ("path", Variable::Path),
+    ("version", Variable::Version),
+    ("status", Variable::Status),
+    ("rule", Variable::Rule),
+    ("owner", Variable::Owner),
+    ("tags", Variable::Tags),
+    ("target", Variable::Target),
+    ("upstream_time", Variable::UpstreamTime),
+    ("bytes", Variable::Bytes),
+    ("referer", Variable::Referer),
+    ("user_agent", Variable::UserAgent),
+    ("attempts", Variable::Attempts),
+    ("environment", Variable::Environment),
+    ("instance", Variable::Instance),
+];
+
+impl FromStr for AccessFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(AccessFormat::Text),
+            "json" => Ok(AccessFormat::Json),
+            "common" => Ok(AccessFormat::Common),
+            "combined" => Ok(AccessFormat::Combined),
+            template if template.contains('$') => parse_template(template),
+            _ => Err(format!(
+                "unknown access log format `{s}`, expected `text`, `json`, `common`, `combined` or a template with `$variable`s"
+            )),
+        }
+    }
+}
+
+/// Splits `template` into literals and variables, `$$` standing for `$`.
+fn parse_template(template: &str) -> Result<AccessFormat, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(index) = rest.find('$') {
+        literal += &rest[..index];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            literal.push('$');
+            rest = after;
+            continue;
+        }
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let name = &rest[..len];
+        let Some((_, variable)) = VARIABLES.iter().find(|(known, _)| *known == name) else {
+            let known: Vec<_> = VARIABLES
+                .iter()
+                .map(|(name, _)| format!("${name}"))
+                .collect();
+            return Err(format!(
+                "unknown access log variable `${name}`, expected one of {}",
+                known.join(", ")
+            ));
+        };
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+        }
+        pieces.push(Piece::Variable(*variable));
+        rest = &rest[len..];
+    }
+    literal += rest;
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(AccessFormat::Template(pieces))
+}
+
+/// What is learned about a request while handling it, for its access log line.
+#[derive(Default)]
+pub struct AccessEntry {
+    pub matched: Option<String>,
+    /// the matched rule's metadata, tags joined by commas
+    pub owner: Option<String>,
+    pub tags: Option<String>,
+    pub forwarded: Option<String>,
+    pub attempts: attempt::Attempts,
+    /// from sending the request upstream until its response headers arrived
+    pub upstream_time: Option<Duration>,
+    /// the matched rule's own `access_log_format`
+    pub format: Option<String>,
+}
+
+/// Emits the access log event of a handled request.
+pub fn record(
+    request: &Request<Body>,
+    remote: client_ip::Remote,
+    requested: &str,
+    entry: &AccessEntry,
+    response: &Response<Body>,
+) {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    let attempts = (entry.attempts.len() > 0).then_some(&entry.attempts);
+    tracing::info!(
+        target: logging::ACCESS,
+        peer = %remote.peer,
+        client = %remote.client,
+        method = ?request.method(),
+        requested,
+        version = ?request.version(),
+        matched = entry.matched.as_deref(),
+        owner = entry.owner.as_deref(),
+        tags = entry.tags.as_deref(),
+        forwarded = entry.forwarded.as_deref(),
+        status = response.status().as_u16(),
+        upstream_time_ms = entry
+            .upstream_time
+            .map(|elapsed| (elapsed.as_secs_f64() * 1e6).round() / 1e3),
+        bytes,
+        referer = header(header::REFERER),
+        user_agent = header(header::USER_AGENT),
+        attempt_count = attempts.map(|attempts| attempts.len()),
+        attempts = attempts.map(tracing::field::display),
+        log_format = entry.format.as_deref(),
+    );
+}
+
+#[derive(Clone)]
+enum Value {
+    /// recorded as a string, quoted in the text format
+    Str(String),
+    /// recorded with its `Debug` or `Display` form
+    Formatted(String),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(value) | Value::Formatted(value) => f.write_str(value),
+            Value::U64(value) => write!(f, "{value}"),
+            Value::I64(value) => write!(f, "{value}"),
+            Value::F64(value) => write!(f, "{value:.3}"),
+            Value::Bool(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl Value {
+    fn json(&self) -> serde_json::Value {
+        match self {
+            Value::Str(value) | Value::Formatted(value) => value.clone().into(),
+            Value::U64(value) => (*value).into(),
+            Value::I64(value) => (*value).into(),
+            Value::F64(value) => (*value).into(),
+            Value::Bool(value) => (*value).into(),
+        }
+    }
+}
+
+/// The fields of an event in the order they were recorded.
+#[derive(Default)]
+struct Fields(Vec<(&'static str, Value)>);
+
+impl Fields {
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(field, _)| *field == name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), Value::Str(value.to_string())));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name(), Value::U64(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name(), Value::I64(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.name(), Value::F64(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name(), Value::Bool(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name(), Value::Formatted(format!("{value:?}"))));
+    }
+}
+
+/// Writes access log events in the configured format, or in the format of
+/// the rule that matched when it has one.
+pub struct AccessLayer {
+    writer: NonBlocking,
+    format: AccessFormat,
+    /// rule formats, parsed the first time they are seen
+    rule_formats: Mutex<HashMap<String, Arc<AccessFormat>>>,
+}
+
+impl AccessLayer {
+    pub fn new(writer: NonBlocking, format: AccessFormat) -> Self {
+        AccessLayer {
+            writer,
+            format,
+            rule_formats: Default::default(),
+        }
+    }
+
+    fn rule_format(&self, format: &str) -> Option<Arc<AccessFormat>> {
+        let mut formats = self.rule_formats.lock().unwrap();
+        if let Some(format) = formats.get(format) {
+            return Some(format.clone());
+        }
+        // rule formats are validated when the config is loaded
+        let parsed = Arc::new(format.parse().ok()?);
+        formats.insert(format.to_string(), Arc::clone(&parsed));
+        Some(parsed)
+    }
+}
+
+impl<S: Subscriber> Layer<S> for AccessLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != logging::ACCESS {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let rule_format = match fields.get("log_format") {
+            Some(Value::Str(format)) => self.rule_format(format),
+            _ => None,
+        };
+        fields.0.retain(|(name, _)| *name != "log_format");
+        let now = SystemTime::now();
+        let mut line = match rule_format.as_deref().unwrap_or(&self.format) {
+            AccessFormat::Text => text(&fields, now),
+            AccessFormat::Json => json(&fields, now),
+            AccessFormat::Common => common(&fields, now),
+            AccessFormat::Combined => {
+                let quoted = |name| quote(fields.get(name).map(ToString::to_string));
+                format!(
+                    "{} {} {}",
+                    common(&fields, now),
+                    quoted("referer"),
+                    quoted("user_agent")
+                )
+            }
+            AccessFormat::Template(pieces) => template(pieces, &fields, now),
+        };
+        line.push('\n');
+        let _ = self.writer.clone().write_all(line.as_bytes());
+    }
+}
+
+fn text(fields: &Fields, now: SystemTime) -> String {
+    let mut line = format!(
+        "{}  INFO {}:",
+        humantime::format_rfc3339_micros(now),
+        logging::ACCESS
+    );
+    for (name, value) in labels::get().iter() {
+        write!(line, " {name}={value:?}").unwrap();
+    }
+    for (name, value) in &fields.0 {
+        match value {
+            Value::Str(value) => write!(line, " {name}={value:?}"),
+            value => write!(line, " {name}={value}"),
+        }
+        .unwrap();
+    }
+    line
+}
+
+fn json(fields: &Fields, now: SystemTime) -> String {
+    let fields: serde_json::Map<_, _> = fields
+        .0
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.json()))
+        .collect();
+    let mut line = serde_json::json!({
+        "timestamp": humantime::format_rfc3339_micros(now).to_string(),
+        "level": "INFO",
+        "fields": fields,
+        "target": logging::ACCESS,
+    });
+    for (name, value) in labels::get().iter() {
+        line[name] = value.into();
+    }
+    line.to_string()
+}
+
+/// `host - - [time] "request line" status bytes`
+fn common(fields: &Fields, now: SystemTime) -> String {
+    let field = |name| fields.get(name).map(ToString::to_string);
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let request_line = format!(
+        "{} {} {}",
+        or_dash(field("method")),
+        or_dash(path(fields)),
+        or_dash(field("version"))
+    );
+    format!(
+        "{} - - [{}] {} {} {}",
+        or_dash(field("client")),
+        clf_time(now),
+        quote(Some(request_line)),
+        or_dash(field("status")),
+        or_dash(field("bytes"))
+    )
+}
+
+fn template(pieces: &[Piece], fields: &Fields, now: SystemTime) -> String {
+    let mut line = String::new();
+    for piece in pieces {
+        let variable = match piece {
+            Piece::Literal(literal) => {
+                line += literal;
+                continue;
+            }
+            Piece::Variable(variable) => variable,
+        };
+        let field = |name| fields.get(name).map(ToString::to_string);
+        let value = match variable {
+            Variable::Time => Some(humantime::format_rfc3339_micros(now).to_string()),
+            Variable::Peer => field("peer"),
+            Variable::Client => field("client"),
+            Variable::Method => field("method"),
+            Variable::Requested => field("requested"),
+            Variable::Path => path(fields),
+            Variable::Version => field("version"),
+            Variable::Status => field("status"),
+            Variable::Rule => field("matched"),
+            Variable::Owner => field("owner"),
+            Variable::Tags => field("tags"),
+            Variable::Target => field("forwarded"),
+            Variable::UpstreamTime => field("upstream_time_ms"),
+            Variable::Bytes => field("bytes"),
+            Variable::Referer => field("referer"),
+            Variable::UserAgent => field("user_agent"),
+            Variable::Attempts => field("attempts"),
+            Variable::Environment => labels::get().environment.clone(),
+            Variable::Instance => labels::get().instance.clone(),
+        };
+        line += value.as_deref().unwrap_or("-");
+    }
+    line
+}
+
+/// The path and query of the requested url, which is prefixed by the host.
+fn path(fields: &Fields) -> Option<String> {
+    let requested = fields.get("requested")?.to_string();
+    Some(requested[requested.find('/')?..].to_string())
+}
+
+fn quote(value: Option<String>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        None => "\"-\"".to_string(),
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC.
+fn clf_time(now: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    // YYYY-MM-DDTHH:MM:SSZ
+    let rfc3339 = humantime::format_rfc3339_seconds(now).to_string();
+    let month = rfc3339[5..7].parse::<usize>().unwrap_or(1);
+    format!(
+        "{}/{}/{}:{} +0000",
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[..4],
+        &rfc3339[11..19]
+    )
+}
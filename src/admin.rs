@@ -0,0 +1,92 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::{labels, redact, report, AppState, ProxyItem};
+
+struct Admin {
+    state: Arc<AppState>,
+    stats: Arc<report::Stats>,
+}
+
+/// Read-only JSON views of the loaded table, served on `--admin-listen`:
+/// `/rules`, `/rules/{name}`, `/config` and `/memory`.
+pub fn router(state: Arc<AppState>, stats: Arc<report::Stats>) -> Router {
+    Router::new()
+        .route("/rules", get(rules))
+        .route("/rules/:name", get(rule))
+        .route("/config", get(config))
+        .route("/memory", get(memory))
+        .with_state(Arc::new(Admin { state, stats }))
+}
+
+async fn rules(State(admin): State<Arc<Admin>>) -> Json<Value> {
+    let table = admin.state.table.read().unwrap().clone();
+    Json(
+        table
+            .items
+            .iter()
+            .map(|item| describe(item, &admin.stats))
+            .collect(),
+    )
+}
+
+async fn rule(State(admin): State<Arc<Admin>>, Path(name): Path<String>) -> Response {
+    let table = admin.state.table.read().unwrap().clone();
+    match table.items.iter().find(|item| item.name == name) {
+        Some(item) => Json(describe(item, &admin.stats)).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("no rule named `{name}`") })),
+        )
+            .into_response(),
+    }
+}
+
+async fn config(State(admin): State<Arc<Admin>>) -> Json<Value> {
+    let table = admin.state.table.read().unwrap().clone();
+    let mut config = table.config.clone();
+    for item in table.items.iter() {
+        if let Some(rule) = config.get_mut(&item.name) {
+            redact::redact(rule);
+        }
+    }
+    Json(config)
+}
+
+async fn memory(State(admin): State<Arc<Admin>>) -> Json<Value> {
+    let table = admin.state.table.read().unwrap().clone();
+    Json(json!({
+        "buffered": table.buffered_memory.usage(),
+        "cache": table.cache_memory.usage(),
+        "labels": labels::get(),
+    }))
+}
+
+fn describe(item: &ProxyItem, stats: &report::Stats) -> Value {
+    let (requests, errors) = stats.rule_counts(&item.name);
+    json!({
+        "name": item.name,
+        "description": item.description,
+        "owner": item.owner,
+        "tags": item.tags,
+        "pattern": item.regex.as_str(),
+        "targets": item
+            .targets
+            .iter()
+            .map(|target| json!({ "template": target.template, "healthy": target.is_healthy() }))
+            .collect::<Vec<_>>(),
+        "fallback": item.fallback.as_ref().map(|target| &target.template),
+        "circuit_breaker": item.breaker.as_ref().map(|breaker| breaker.status()),
+        "requests": requests,
+        "errors": errors,
+        "labels": labels::get(),
+        "config": redact::redacted(&item.config),
+    })
+}
@@ -0,0 +1,162 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::labels;
+
+/// Buckets the window is divided into; older buckets drop out as a whole.
+const BUCKETS: u32 = 30;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ErrorBudgetConfig {
+    /// share of 5xx responses, such as `0.05`, above which an alert is raised
+    pub max_error_rate: f64,
+    #[serde(default = "default_window", with = "humantime_serde")]
+    pub window: Duration,
+    /// requests the window must hold before its error rate counts
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+    /// URL the alert and the recovery are posted to as JSON
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_window() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_min_requests() -> u64 {
+    20
+}
+
+struct Bucket {
+    started: Instant,
+    requests: u64,
+    errors: u64,
+}
+
+#[derive(Default)]
+struct Window {
+    buckets: VecDeque<Bucket>,
+    alerting: bool,
+}
+
+#[derive(Serialize)]
+struct Alert<'a> {
+    #[serde(flatten)]
+    labels: &'static labels::Labels,
+    rule: &'a str,
+    state: &'static str,
+    error_rate: f64,
+    requests: u64,
+    errors: u64,
+    window_secs: u64,
+    max_error_rate: f64,
+}
+
+/// The rolling error rate of a rule, alerting when it crosses the budget
+/// and again when it is back within.
+pub struct ErrorBudget {
+    config: ErrorBudgetConfig,
+    window: Mutex<Window>,
+}
+
+impl ErrorBudget {
+    pub fn new(config: &ErrorBudgetConfig) -> anyhow::Result<Self> {
+        if !(0.0..1.0).contains(&config.max_error_rate) {
+            anyhow::bail!("`error_budget.max_error_rate` must be at least 0 and below 1");
+        }
+        if config.window.is_zero() {
+            anyhow::bail!("`error_budget.window` must not be 0");
+        }
+        Ok(ErrorBudget {
+            config: config.clone(),
+            window: Default::default(),
+        })
+    }
+
+    /// Counts a response of rule `rule` with `status`.
+    pub fn record(&self, rule: &str, status: StatusCode) {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        while window
+            .buckets
+            .front()
+            .is_some_and(|bucket| now.duration_since(bucket.started) >= self.config.window)
+        {
+            window.buckets.pop_front();
+        }
+        let width = self.config.window / BUCKETS;
+        match window.buckets.back_mut() {
+            Some(bucket) if now.duration_since(bucket.started) < width => {}
+            _ => window.buckets.push_back(Bucket {
+                started: now,
+                requests: 0,
+                errors: 0,
+            }),
+        }
+        let bucket = window.buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        bucket.errors += u64::from(status.is_server_error());
+
+        let (requests, errors) = window
+            .buckets
+            .iter()
+            .fold((0, 0), |(requests, errors), bucket| {
+                (requests + bucket.requests, errors + bucket.errors)
+            });
+        if requests < self.config.min_requests {
+            return;
+        }
+        let error_rate = errors as f64 / requests as f64;
+        let exceeded = error_rate > self.config.max_error_rate;
+        if exceeded == window.alerting {
+            return;
+        }
+        window.alerting = exceeded;
+        drop(window);
+
+        let alert = Alert {
+            labels: labels::get(),
+            rule,
+            state: if exceeded { "exceeded" } else { "recovered" },
+            error_rate,
+            requests,
+            errors,
+            window_secs: self.config.window.as_secs(),
+            max_error_rate: self.config.max_error_rate,
+        };
+        if exceeded {
+            tracing::warn!(
+                rule,
+                error_rate,
+                requests,
+                errors,
+                window = %humantime::format_duration(self.config.window),
+                "error budget exceeded"
+            );
+        } else {
+            tracing::info!(rule, error_rate, requests, errors, "error budget recovered");
+        }
+        if let Some(webhook) = &self.config.webhook {
+            let (webhook, body) = (webhook.clone(), serde_json::to_vec(&alert).unwrap());
+            tokio::spawn(async move {
+                let sent = reqwest::Client::new()
+                    .post(&webhook)
+                    .timeout(Duration::from_secs(10))
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(err) = sent {
+                    tracing::warn!(webhook, error = ?err, "error budget alert not delivered");
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// One try at forwarding a request to an upstream target.
+pub struct Attempt {
+    target: String,
+    elapsed: Duration,
+    outcome: Result<u16, String>,
+}
+
+/// Every upstream attempt made for a request, for the access log.
+#[derive(Default)]
+pub struct Attempts(Vec<Attempt>);
+
+impl Attempts {
+    /// Records an attempt started at `started` that ended with `outcome`.
+    pub fn record<T>(
+        &mut self,
+        target: &str,
+        started: Instant,
+        outcome: &anyhow::Result<T>,
+        status: impl FnOnce(&T) -> u16,
+    ) {
+        self.0.push(Attempt {
+            target: target.to_string(),
+            elapsed: started.elapsed(),
+            outcome: match outcome {
+                Ok(value) => Ok(status(value)),
+                Err(err) => Err(err.root_cause().to_string()),
+            },
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl fmt::Display for Attempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, attempt) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_str("; ")?;
+            }
+            match &attempt.outcome {
+                Ok(status) => write!(f, "{} {}", attempt.target, status)?,
+                Err(err) => write!(f, "{} error({})", attempt.target, err)?,
+            }
+            write!(f, " {}ms", attempt.elapsed.as_millis())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt, net::Ipv6Addr};
+
+/// Checks the host and port of `template` as far as they are literal: IPv6
+/// addresses must be bracketed and ports within 1-65535. Parts taken from
+/// capture groups, such as the port of `http://host:$1/`, are checked per
+/// request instead.
+pub fn validate_template(template: &str) -> anyhow::Result<()> {
+    let literal = template.split('$').next().unwrap_or_default();
+    let Some((scheme, rest)) = literal.split_once("://") else {
+        return Ok(());
+    };
+    let end = rest.find(['/', '?', '#']);
+    // the authority is complete unless a substitution continues it
+    let complete = end.is_some() || literal.len() == template.len();
+    let authority = &rest[..end.unwrap_or(rest.len())];
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+
+    let port = if let Some(bracketed) = authority.strip_prefix('[') {
+        let Some((address, after)) = bracketed.split_once(']') else {
+            if complete {
+                anyhow::bail!("target `{template}` has an unclosed `[` in its host");
+            }
+            return Ok(());
+        };
+        if address.parse::<Ipv6Addr>().is_err() {
+            anyhow::bail!("target `{template}` has an invalid IPv6 address `{address}`");
+        }
+        match after.strip_prefix(':') {
+            Some(port) => Some(port),
+            None if after.is_empty() => None,
+            None => anyhow::bail!("target `{template}` has `{after}` after its IPv6 address"),
+        }
+    } else {
+        if authority.matches(':').count() > 1 {
+            let address = authority
+                .rsplit_once(':')
+                .filter(|(address, _)| address.parse::<Ipv6Addr>().is_ok())
+                .map_or(authority, |(address, _)| address);
+            anyhow::bail!("target `{template}` must bracket its IPv6 address as `[{address}]`");
+        }
+        authority.split_once(':').map(|(_, port)| port)
+    };
+
+    if let Some(port) = port {
+        if !port.bytes().all(|b| b.is_ascii_digit()) {
+            anyhow::bail!("target `{template}` has an invalid port `{port}`");
+        }
+        if complete && !port.parse::<u16>().is_ok_and(|port| port != 0) {
+            anyhow::bail!("target `{template}` has port `{port}` outside of 1-65535");
+        }
+    }
+    if complete {
+        reqwest::Url::parse(&format!("{scheme}://{authority}/"))
+            .map_err(|err| anyhow::anyhow!("target `{template}` has an invalid host: {err}"))?;
+    }
+    Ok(())
+}
+
+/// Expands `template` for `captures` like `Regex::replace` does, bracketing
+/// IPv6 addresses that capture groups put into the host, so `http://$1:8080/`
+/// works for `::1` as well.
+pub fn expand(captures: &regex::Captures<'_>, template: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(index) = rest.find('$') {
+        expanded += &rest[..index];
+        rest = &rest[index + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => braced.split_once('}').unwrap_or(("", rest)),
+            None => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                rest.split_at(len)
+            }
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            continue;
+        }
+        let value = match name.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(name),
+        }
+        .map_or("", |group| group.as_str());
+        let in_host = expanded
+            .split_once("://")
+            .is_some_and(|(_, rest)| !rest.contains(['/', '?', '#']));
+        if in_host && !expanded.ends_with('[') && value.parse::<Ipv6Addr>().is_ok() {
+            expanded += &format!("[{value}]");
+        } else {
+            expanded += value;
+        }
+        rest = after;
+    }
+    expanded + rest
+}
+
+/// Parses a rewritten target `url`, whose host or port may come from the
+/// request.
+pub fn parse(url: &str) -> Result<reqwest::Url, InvalidTarget> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|err| InvalidTarget(format!("`{url}`: {err}")))?;
+    if parsed.port() == Some(0) {
+        return Err(InvalidTarget(format!("`{url}`: port 0")));
+    }
+    if parsed.host_str().is_none() {
+        return Err(InvalidTarget(format!("`{url}`: no host")));
+    }
+    Ok(parsed)
+}
+
+/// Raised when a rewritten target is not a valid url.
+#[derive(Debug)]
+pub struct InvalidTarget(pub String);
+
+impl fmt::Display for InvalidTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid target {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTarget {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand_with(pattern: &str, url: &str, template: &str) -> String {
+        let regex = regex::Regex::new(pattern).unwrap();
+        expand(&regex.captures(url).unwrap(), template)
+    }
+
+    #[test]
+    fn expands_like_regex_replace() {
+        let pattern = r"^(?P<host>[^/]*)/(.*)";
+        let url = "example.com/a/b?c";
+        for template in [
+            "http://up/$2",
+            "http://up/${2}x",
+            "http://$host:8080/$2",
+            "http://up/$$2",
+            "http://up/$2a",
+            "http://up/$",
+        ] {
+            let regex = regex::Regex::new(pattern).unwrap();
+            assert_eq!(
+                expand_with(pattern, url, template),
+                regex.replace(url, template),
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts_only() {
+        let pattern = r"^([^/]*)/(.*)";
+        assert_eq!(
+            expand_with(pattern, "::1/x", "http://$1:8080/$2"),
+            "http://[::1]:8080/x"
+        );
+        assert_eq!(
+            expand_with(pattern, "::1/x", "http://[$1]:8080/$2"),
+            "http://[::1]:8080/x"
+        );
+        assert_eq!(
+            expand_with(pattern, "::1/x", "http://up/$1"),
+            "http://up/::1"
+        );
+    }
+
+    #[test]
+    fn validates_literal_authorities() {
+        assert!(validate_template("http://127.0.0.1:3400/$1").is_ok());
+        assert!(validate_template("http://[::1]:3400/$1").is_ok());
+        assert!(validate_template("http://host:$1/").is_ok());
+        assert!(validate_template("http://::1:3400/").is_err());
+        assert!(validate_template("http://host:0/").is_err());
+        assert!(validate_template("http://host:70000/").is_err());
+        assert!(validate_template("http://[::1/").is_err());
+    }
+
+    #[test]
+    fn rejects_targets_without_host_or_port() {
+        assert!(parse("http://up:8080/x").is_ok());
+        assert!(parse("http://up:0/x").is_err());
+        assert!(parse("unix:/tmp/app.sock").is_err());
+    }
+}
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    #[default]
+    RoundRobin,
+    IpHash,
+}
+
+pub struct Balancer {
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(strategy: BalanceStrategy) -> Self {
+        Balancer {
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+
+    /// Picks the index of the target serving a request from `client_ip`.
+    pub fn pick(&self, len: usize, client_ip: IpAddr) -> usize {
+        if len <= 1 {
+            return 0;
+        }
+        match self.strategy {
+            BalanceStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+            BalanceStrategy::IpHash => (ip_hash(client_ip) % len as u64) as usize,
+        }
+    }
+}
+
+/// The targets of a rule, given either as one `target` or as a `targets`
+/// list spread by round robin unless another strategy is chosen.
+pub fn targets(target: Option<&String>, targets: &[String]) -> anyhow::Result<Vec<String>> {
+    match (target, targets.is_empty()) {
+        (Some(target), true) => Ok(vec![target.clone()]),
+        (None, false) => Ok(targets.to_vec()),
+        (Some(_), false) => anyhow::bail!("`target` and `targets` are exclusive"),
+        (None, true) => anyhow::bail!("missing `target`"),
+    }
+}
+
+/// FNV-1a over the address octets, which stays stable across restarts so a
+/// client keeps landing on the same target.
+fn ip_hash(ip: IpAddr) -> u64 {
+    let octets = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.octets().to_vec(),
+            None => ip.octets().to_vec(),
+        },
+    };
+    octets.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_ip;
+    use axum::http::HeaderMap;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn round_robin_cycles_through_targets() {
+        let balancer = Balancer::new(BalanceStrategy::RoundRobin);
+        let client = Ipv4Addr::LOCALHOST.into();
+        let picks: Vec<_> = (0..5).map(|_| balancer.pick(3, client)).collect();
+        assert_eq!(picks, [0, 1, 2, 0, 1]);
+        assert_eq!(balancer.pick(1, client), 0);
+    }
+
+    #[test]
+    fn targets_come_from_target_or_targets() {
+        let one = "http://a/$1".to_string();
+        let many = ["http://a/$1".to_string(), "http://b/$1".to_string()];
+        assert_eq!(targets(Some(&one), &[]).unwrap(), [one.as_str()]);
+        assert_eq!(targets(None, &many).unwrap(), many);
+        assert!(targets(Some(&one), &many).is_err());
+        assert!(targets(None, &[]).is_err());
+    }
+
+    #[test]
+    fn ip_hash_is_stable_per_client() {
+        let balancer = Balancer::new(BalanceStrategy::IpHash);
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let pick = balancer.pick(4, client);
+        assert!((0..10).all(|_| balancer.pick(4, client) == pick));
+        // the mapped form of an IPv4 client lands on the same target
+        assert_eq!(
+            balancer.pick(4, "::ffff:203.0.113.7".parse().unwrap()),
+            pick
+        );
+    }
+
+    #[test]
+    fn ip_hash_follows_clients_behind_trusted_proxies() {
+        let balancer = Balancer::new(BalanceStrategy::IpHash);
+        let trusted = client_ip::TrustedProxies::default();
+        let pick = |peer: &str, forwarded_for: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            let client = client_ip::resolve(peer.parse().unwrap(), &headers, &trusted);
+            balancer.pick(4, client)
+        };
+        let direct = balancer.pick(4, "203.0.113.7".parse().unwrap());
+        assert_eq!(pick("127.0.0.1:40000", "203.0.113.7"), direct);
+        assert_eq!(pick("127.0.0.2:40001", "203.0.113.7"), direct);
+        // an untrusted peer can't pick its target with the header
+        assert_eq!(
+            pick("198.51.100.1:40000", "203.0.113.7"),
+            balancer.pick(4, "198.51.100.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ip_hash_spreads_clients() {
+        let balancer = Balancer::new(BalanceStrategy::IpHash);
+        let mut counts = [0; 4];
+        for last in 0..=255 {
+            counts[balancer.pick(4, Ipv4Addr::new(198, 51, 100, last).into())] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 32), "{counts:?}");
+    }
+}
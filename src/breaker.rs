@@ -0,0 +1,234 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CircuitBreakerConfig {
+    /// consecutive failed upstream requests that open the circuit
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    /// time requests are refused with 503 before a probe request is let
+    /// through
+    #[serde(default = "default_cooldown", with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+fn default_failures() -> u32 {
+    5
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// a probe request is on its way; another one is let through if it
+    /// hasn't reported back by `until`
+    HalfOpen {
+        until: Instant,
+    },
+}
+
+/// A request `admit` let through, tagged with the state it was admitted in.
+#[derive(Clone, Copy)]
+pub struct Admission {
+    generation: u64,
+}
+
+/// Stops sending requests to the upstream of a rule after consecutive
+/// failures, so clients fail fast instead of waiting on a dead backend.
+/// Failures are errors and 5xx responses.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<(State, u64)>,
+    /// times the circuit opened since the rule was loaded
+    opened: AtomicU64,
+}
+
+/// The state of a circuit as the admin API shows it.
+#[derive(Serialize)]
+pub struct Status {
+    pub state: &'static str,
+    /// consecutive failures counted towards opening the circuit
+    pub failures: u32,
+    pub opened: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: Mutex::new((State::Closed { failures: 0 }, 0)),
+            opened: AtomicU64::new(0),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let (state, failures) = match self.state.lock().unwrap().0 {
+            State::Closed { failures } => ("closed", failures),
+            State::Open { .. } => ("open", self.config.failures),
+            State::HalfOpen { .. } => ("half_open", self.config.failures),
+        };
+        Status {
+            state,
+            failures,
+            opened: self.opened.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether a request of rule `rule` may be sent upstream, or else the
+    /// time left until the next probe.
+    pub fn admit(&self, rule: &str) -> Result<Admission, Duration> {
+        let mut guard = self.state.lock().unwrap();
+        let (state, generation) = &mut *guard;
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(Admission {
+                generation: *generation,
+            }),
+            State::Open { until } | State::HalfOpen { until } if now < until => Err(until - now),
+            State::Open { .. } | State::HalfOpen { .. } => {
+                tracing::info!(rule, "circuit half-open, probing upstream");
+                *state = State::HalfOpen {
+                    until: now + self.config.cooldown,
+                };
+                *generation += 1;
+                Ok(Admission {
+                    generation: *generation,
+                })
+            }
+        }
+    }
+
+    /// Counts the outcome of a request `admit` let through. Outcomes of
+    /// requests admitted before the circuit last opened, or of probes given
+    /// up on, are ignored: only the current probe may close it again.
+    pub fn record(&self, rule: &str, admission: Admission, success: bool) {
+        let mut guard = self.state.lock().unwrap();
+        let (state, generation) = &mut *guard;
+        if admission.generation != *generation {
+            return;
+        }
+        match (&*state, success) {
+            (State::Closed { failures: 0 }, true) => {}
+            (_, true) => {
+                if !matches!(*state, State::Closed { .. }) {
+                    tracing::info!(rule, "circuit closed");
+                }
+                *state = State::Closed { failures: 0 };
+            }
+            (State::Closed { failures }, false) if failures + 1 < self.config.failures => {
+                *state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            (_, false) => {
+                tracing::warn!(
+                    rule,
+                    cooldown = %humantime::format_duration(self.config.cooldown),
+                    "circuit opened"
+                );
+                *state = State::Open {
+                    until: Instant::now() + self.config.cooldown,
+                };
+                *generation += 1;
+                self.opened.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failures: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig { failures, cooldown })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        let first = breaker.admit("r").unwrap();
+        breaker.record("r", first, false);
+        let second = breaker.admit("r").unwrap();
+        breaker.record("r", second, true);
+        let third = breaker.admit("r").unwrap();
+        breaker.record("r", third, false);
+        assert!(breaker.admit("r").is_ok());
+
+        let fourth = breaker.admit("r").unwrap();
+        breaker.record("r", fourth, false);
+        assert!(breaker.admit("r").is_err());
+    }
+
+    #[test]
+    fn reports_its_status() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        let status = breaker.status();
+        assert_eq!(
+            (status.state, status.failures, status.opened),
+            ("closed", 0, 0)
+        );
+        for _ in 0..2 {
+            let admission = breaker.admit("r").unwrap();
+            breaker.record("r", admission, false);
+        }
+        let status = breaker.status();
+        assert_eq!(
+            (status.state, status.failures, status.opened),
+            ("open", 2, 1)
+        );
+    }
+
+    #[test]
+    fn ignores_late_successes_admitted_before_opening() {
+        let breaker = breaker(1, Duration::ZERO);
+        let late = breaker.admit("r").unwrap();
+        let failed = breaker.admit("r").unwrap();
+        breaker.record("r", failed, false);
+
+        let probe = breaker.admit("r").unwrap();
+        breaker.record("r", late, true);
+        assert!(matches!(
+            breaker.state.lock().unwrap().0,
+            State::HalfOpen { .. }
+        ));
+        breaker.record("r", probe, true);
+        assert!(matches!(
+            breaker.state.lock().unwrap().0,
+            State::Closed { .. }
+        ));
+    }
+
+    #[test]
+    fn only_the_latest_probe_counts() {
+        let breaker = breaker(1, Duration::ZERO);
+        let failed = breaker.admit("r").unwrap();
+        breaker.record("r", failed, false);
+
+        let given_up = breaker.admit("r").unwrap();
+        let probe = breaker.admit("r").unwrap();
+        breaker.record("r", given_up, true);
+        assert!(matches!(
+            breaker.state.lock().unwrap().0,
+            State::HalfOpen { .. }
+        ));
+        breaker.record("r", probe, false);
+        assert!(matches!(
+            breaker.state.lock().unwrap().0,
+            State::Open { .. }
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue},
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use std::sync::Arc;
+
+use crate::{errors, memory};
+
+/// Reads the upstream body into memory up to `limit` bytes so the upstream
+/// connection is released before a slow client has consumed the response.
+/// Bodies growing beyond the limit, or while the buffers of all responses
+/// exhaust `memory`, are streamed from the point the buffer filled up.
+pub async fn collect(
+    body: impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static,
+    limit: usize,
+    memory: &Arc<memory::Budget>,
+    headers: &mut HeaderMap,
+) -> anyhow::Result<Body> {
+    let mut body = Box::pin(body);
+    let mut buffered = BytesMut::new();
+    let mut reservation = memory.reservation();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        let reserved = reservation.grow(chunk.len());
+        buffered.extend_from_slice(&chunk);
+        if buffered.len() > limit || !reserved {
+            let head = stream::once(async move { Ok(buffered.freeze()) });
+            return Ok(Body::wrap_stream(head.chain(body)));
+        }
+    }
+    headers.remove(header::TRANSFER_ENCODING);
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(buffered.len()));
+    // the buffer counts against the budget until it has been sent
+    Ok(Body::wrap_stream(stream::once(async move {
+        let _reservation = reservation;
+        anyhow::Ok(buffered.freeze())
+    })))
+}
+
+/// Fails `body` once it grows beyond `limit` bytes, so an upstream can't
+/// stream an unbounded response through rule `rule`. The client connection
+/// is aborted, as the response head has already been sent.
+pub fn cap(
+    body: impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static,
+    limit: Option<usize>,
+    rule: String,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static {
+    stream::unfold(Some((Box::pin(body), 0)), move |state| {
+        let rule = rule.clone();
+        async move {
+            let (mut body, received) = state?;
+            let chunk = match body.next().await? {
+                Ok(chunk) => chunk,
+                Err(err) => return Some((Err(err), None)),
+            };
+            let received = received + chunk.len();
+            match limit {
+                Some(limit) if received > limit => {
+                    tracing::error!(
+                        rule,
+                        limit,
+                        "upstream response exceeds max_response_size, aborted"
+                    );
+                    Some((Err(errors::ResponseTooLarge(limit).into()), None))
+                }
+                _ => Some((Ok(chunk), Some((body, received)))),
+            }
+        }
+    })
+}
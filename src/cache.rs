@@ -0,0 +1,574 @@
+use axum::{
+    body::Body,
+    http::{
+        header::{self, HeaderName},
+        HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+    },
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::memory;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CacheConfig {
+    /// time a stored response is served before it is fetched again, unless
+    /// its `Cache-Control` or `Expires` header says otherwise
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+    /// responses kept at most; the least recently used are evicted first
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// larger responses are passed through without being stored
+    #[serde(default = "default_max_entry_size")]
+    pub max_entry_size: bytesize::ByteSize,
+    /// variants of one url kept at most, told apart by the request headers
+    /// their `Vary` names; the oldest is evicted first
+    #[serde(default = "default_max_variants")]
+    pub max_variants: usize,
+    /// request headers responses may vary by to be stored, any when empty
+    #[serde(default)]
+    pub vary_headers: Vec<String>,
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+fn default_max_entry_size() -> bytesize::ByteSize {
+    bytesize::ByteSize::mib(1)
+}
+
+fn default_max_variants() -> usize {
+    16
+}
+
+#[derive(Clone)]
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    fresh_for: Duration,
+    /// taken from the cache budget once stored
+    memory: Option<Arc<memory::Reservation>>,
+}
+
+impl Entry {
+    fn response(self, age: Duration) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+            .headers_mut()
+            .insert(header::AGE, HeaderValue::from(age.as_secs()));
+        response
+    }
+}
+
+pub enum Lookup {
+    Fresh(Response<Body>),
+    /// a stored response that is stale but has validators to ask the
+    /// upstream whether it is still current
+    Stale(Stale),
+    Miss,
+}
+
+/// A stale entry, with the key of the variant it was stored under.
+pub struct Stale(Entry, String);
+
+impl Stale {
+    /// The `If-None-Match` and `If-Modified-Since` headers revalidating the
+    /// response.
+    pub fn conditions(&self) -> HeaderMap {
+        let mut conditions = HeaderMap::new();
+        if let Some(etag) = self.0.headers.get(header::ETAG) {
+            conditions.insert(header::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(modified) = self.0.headers.get(header::LAST_MODIFIED) {
+            conditions.insert(header::IF_MODIFIED_SINCE, modified.clone());
+        }
+        conditions
+    }
+}
+
+/// Upstream responses to GET and HEAD requests of a rule, kept in memory by
+/// method and url, and by the request headers their `Vary` names.
+pub struct Cache {
+    ttl: Duration,
+    max_entry_size: usize,
+    entries: Mutex<lru::LruCache<String, Entry>>,
+    /// the variants stored by method and url
+    vary: Mutex<lru::LruCache<String, Variants>>,
+    max_variants: usize,
+    /// empty allows any
+    vary_headers: Vec<HeaderName>,
+    /// shared by the caches of all rules
+    memory: Arc<memory::Budget>,
+}
+
+/// The `Vary` header names of the latest response to a method and url, and
+/// the keys of its variants from the oldest.
+#[derive(Default)]
+struct Variants {
+    names: Vec<HeaderName>,
+    keys: VecDeque<String>,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig, memory: Arc<memory::Budget>) -> anyhow::Result<Self> {
+        let Some(max_entries) = NonZeroUsize::new(config.max_entries) else {
+            anyhow::bail!("`cache.max_entries` must be at least 1");
+        };
+        if config.max_variants == 0 {
+            anyhow::bail!("`cache.max_variants` must be at least 1");
+        }
+        let vary_headers = config
+            .vary_headers
+            .iter()
+            .map(|name| {
+                name.parse()
+                    .map_err(|err| anyhow::anyhow!("`cache.vary_headers`: `{name}`: {err}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Cache {
+            ttl: config.ttl,
+            max_entry_size: config.max_entry_size.as_u64() as usize,
+            entries: Mutex::new(lru::LruCache::new(max_entries)),
+            vary: Mutex::new(lru::LruCache::new(max_entries)),
+            max_variants: config.max_variants,
+            vary_headers,
+            memory,
+        })
+    }
+
+    /// The key `request` for `url` is cached under, unless it may not be
+    /// answered from the cache. Requests with credentials or cookies always
+    /// go upstream, as their responses may be meant for that client only.
+    pub fn key(request: &Request<Body>, url: &str) -> Option<String> {
+        let method = request.method();
+        if method != Method::GET && method != Method::HEAD
+            || request.headers().contains_key(header::AUTHORIZATION)
+            || request.headers().contains_key(header::COOKIE)
+            || request.headers().contains_key(header::UPGRADE)
+        {
+            return None;
+        }
+        Some(format!("{method} {url}"))
+    }
+
+    /// The stored response for `key` matching the `request` headers the
+    /// response varies by, unless it is stale. Stale responses without
+    /// validators are dropped.
+    pub fn lookup(&self, key: &str, request: &HeaderMap) -> Lookup {
+        let names = self
+            .vary
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|variants| variants.names.clone());
+        let key = variant(key, names.as_deref().unwrap_or_default(), request);
+        let mut entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&key) else {
+            return Lookup::Miss;
+        };
+        let age = entry.stored.elapsed();
+        if age < entry.fresh_for {
+            return Lookup::Fresh(entry.clone().response(age));
+        }
+        if entry.headers.contains_key(header::ETAG)
+            || entry.headers.contains_key(header::LAST_MODIFIED)
+        {
+            return Lookup::Stale(Stale(entry.clone(), key));
+        }
+        entries.pop(&key);
+        Lookup::Miss
+    }
+
+    /// The key a response with `headers` to `request` is stored under: `key`
+    /// and the values of the request headers its `Vary` names, which are
+    /// remembered for the lookups of `key`. Beyond `max_variants`, the oldest
+    /// variant of `key` is evicted.
+    pub fn variant_key(&self, key: String, headers: &HeaderMap, request: &HeaderMap) -> String {
+        let mut names = vary(headers);
+        names.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        names.dedup();
+        let variant = variant(&key, &names, request);
+
+        let mut vary = self.vary.lock().unwrap();
+        let variants = vary.get_or_insert_mut(key, Default::default);
+        let mut entries = self.entries.lock().unwrap();
+        if variants.names != names {
+            // the variants stored so far can't be looked up anymore
+            for key in variants.keys.drain(..) {
+                entries.pop(&key);
+            }
+            variants.names = names;
+        }
+        if !variants.keys.contains(&variant) {
+            variants.keys.push_back(variant.clone());
+        }
+        while variants.keys.len() > self.max_variants {
+            if let Some(oldest) = variants.keys.pop_front() {
+                entries.pop(&oldest);
+            }
+        }
+        variant
+    }
+
+    /// Serves `stale` again after the upstream answered its revalidation
+    /// with 304 and `headers`, which replace the stored ones.
+    pub fn revalidated(&self, stale: Stale, headers: &HeaderMap) -> Response<Body> {
+        let Stale(mut entry, key) = stale;
+        for name in headers.keys() {
+            if name != header::CONTENT_LENGTH && name != header::TRANSFER_ENCODING {
+                entry.headers.remove(name);
+                for value in headers.get_all(name) {
+                    entry.headers.append(name, value.clone());
+                }
+            }
+        }
+        entry.stored = Instant::now();
+        match self.freshness(entry.status, &entry.headers) {
+            Some(fresh_for) => {
+                entry.fresh_for = fresh_for;
+                self.insert(key, entry.clone());
+            }
+            None => {
+                self.entries.lock().unwrap().pop(&key);
+            }
+        }
+        entry.response(Duration::ZERO)
+    }
+
+    /// How long a response with `status` and `headers` may be served without
+    /// asking the upstream, or `None` if it may not be stored at all: only
+    /// successful ones are, and none that are private to a single client.
+    pub fn freshness(&self, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        if status != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        // varying by something other than request headers, or by headers
+        // outside the allowed ones
+        let vary_any = headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| name.trim() == "*");
+        let vary_other = !self.vary_headers.is_empty()
+            && vary(headers)
+                .iter()
+                .any(|name| !self.vary_headers.contains(name));
+        if vary_any || vary_other {
+            return None;
+        }
+        let (mut max_age, mut shared_max_age) = (None, None);
+        for directive in headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+        {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let seconds = value.trim().trim_matches('"').parse().ok();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" | "private" => return None,
+                "no-cache" => return Some(Duration::ZERO),
+                "max-age" => max_age = seconds.map(Duration::from_secs),
+                "s-maxage" => shared_max_age = seconds.map(Duration::from_secs),
+                _ => {}
+            }
+        }
+        let lifetime = shared_max_age.or(max_age).or_else(|| {
+            let expires = http_date(headers, header::EXPIRES)?;
+            let date = http_date(headers, header::DATE).unwrap_or_else(SystemTime::now);
+            Some(expires.duration_since(date).unwrap_or_default())
+        });
+        let age = headers
+            .get(header::AGE)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map_or(Duration::ZERO, Duration::from_secs);
+        let fresh_for = lifetime.unwrap_or(self.ttl).saturating_sub(age);
+        // a response that is always stale is only worth keeping to revalidate
+        let validated =
+            headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+        (!fresh_for.is_zero() || validated).then_some(fresh_for)
+    }
+
+    /// Stores `entry`, evicting the least recently used entries of this
+    /// cache while the memory budget is exhausted. It isn't stored if that
+    /// doesn't make room.
+    fn insert(&self, key: String, mut entry: Entry) {
+        entry.memory = None;
+        let size = key.len()
+            + entry.body.len()
+            + entry
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        let mut entries = self.entries.lock().unwrap();
+        entries.pop(&key);
+        while !self.memory.fits(size) && entries.pop_lru().is_some() {}
+        let Some(reservation) = self.memory.try_reserve(size) else {
+            return;
+        };
+        entry.memory = Some(Arc::new(reservation));
+        entries.put(key, entry);
+    }
+}
+
+/// What a response is stored as once its body has been read completely.
+pub struct Store {
+    pub cache: Arc<Cache>,
+    pub key: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub fresh_for: Duration,
+}
+
+impl Store {
+    fn finish(mut self, body: BytesMut) {
+        self.headers.remove(header::TRANSFER_ENCODING);
+        self.cache.insert(
+            self.key,
+            Entry {
+                status: self.status,
+                headers: self.headers,
+                body: body.freeze(),
+                stored: Instant::now(),
+                fresh_for: self.fresh_for,
+                memory: None,
+            },
+        );
+    }
+}
+
+/// Passes `body` through, storing a copy with `store` once it has been read
+/// to the end, or to its Content-Length, after which the server stops
+/// reading. Bodies that fail or grow beyond the size limit aren't stored.
+pub fn tee(
+    body: impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static,
+    store: Option<Store>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static {
+    let copy = store.map(|store| (store, BytesMut::new()));
+    stream::unfold(Some((Box::pin(body), copy)), |state| async move {
+        let (mut body, mut copy) = state?;
+        let Some(chunk) = body.next().await else {
+            if let Some((store, copy)) = copy {
+                store.finish(copy);
+            }
+            return None;
+        };
+        match &chunk {
+            Ok(bytes) => {
+                copy = copy
+                    .filter(|(store, copy)| copy.len() + bytes.len() <= store.cache.max_entry_size);
+                if let Some((store, buffer)) = &mut copy {
+                    buffer.extend_from_slice(bytes);
+                    if content_length(&store.headers) == Some(buffer.len()) {
+                        let (store, buffer) = copy.take().unwrap();
+                        store.finish(buffer);
+                    }
+                }
+            }
+            Err(_) => copy = None,
+        }
+        Some((chunk, Some((body, copy))))
+    })
+}
+
+/// The header names `headers` vary by.
+fn vary(headers: &HeaderMap) -> Vec<HeaderName> {
+    headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect()
+}
+
+/// `key` followed by the values `request` has for the header `names`.
+fn variant(key: &str, names: &[HeaderName], request: &HeaderMap) -> String {
+    let mut variant = key.to_string();
+    for name in names {
+        let values: Vec<&str> = request
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        variant.push_str(&format!("\n{name}: {}", values.join(", ")));
+    }
+    variant
+}
+
+fn content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn http_date(headers: &HeaderMap, name: header::HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> Cache {
+        let config = CacheConfig {
+            ttl: Duration::from_secs(60),
+            max_entries: 10,
+            max_entry_size: bytesize::ByteSize::kib(1),
+            max_variants: 2,
+            vary_headers: vec!["accept-encoding".to_string()],
+        };
+        Cache::new(&config, memory::Budget::new("cache", None)).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    fn request(pairs: &[(&'static str, &'static str)]) -> Request<Body> {
+        let mut request = Request::new(Body::empty());
+        *request.headers_mut() = headers(pairs);
+        request
+    }
+
+    fn store(cache: &Arc<Cache>, key: String, headers: HeaderMap) {
+        let store = Store {
+            cache: cache.clone(),
+            key,
+            status: StatusCode::OK,
+            headers,
+            fresh_for: Duration::from_secs(60),
+        };
+        store.finish(BytesMut::from("body"));
+    }
+
+    #[test]
+    fn key_skips_requests_with_credentials() {
+        assert_eq!(
+            Cache::key(&request(&[]), "host/path").as_deref(),
+            Some("GET host/path")
+        );
+        assert!(Cache::key(&request(&[("authorization", "Basic eA==")]), "host/path").is_none());
+        assert!(Cache::key(&request(&[("cookie", "session=1")]), "host/path").is_none());
+        let mut post = request(&[]);
+        *post.method_mut() = Method::POST;
+        assert!(Cache::key(&post, "host/path").is_none());
+    }
+
+    #[test]
+    fn freshness_follows_cache_control() {
+        let cache = cache();
+        let fresh = |pairs| cache.freshness(StatusCode::OK, &headers(pairs));
+        assert_eq!(fresh(&[]), Some(Duration::from_secs(60)));
+        assert_eq!(
+            fresh(&[("cache-control", "public, max-age=10")]),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            fresh(&[("cache-control", "max-age=10, s-maxage=20")]),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(
+            fresh(&[("cache-control", "max-age=10"), ("age", "4")]),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(fresh(&[("cache-control", "no-store")]), None);
+        assert_eq!(fresh(&[("cache-control", "private")]), None);
+        assert_eq!(fresh(&[("cache-control", "max-age=0")]), None);
+        assert_eq!(
+            fresh(&[("cache-control", "max-age=0"), ("etag", "\"v1\"")]),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(fresh(&[("set-cookie", "a=b")]), None);
+        assert_eq!(fresh(&[("vary", "Accept-Encoding, *")]), None);
+        assert_eq!(fresh(&[("vary", "Accept-Encoding, User-Agent")]), None);
+        assert_eq!(cache.freshness(StatusCode::NOT_FOUND, &headers(&[])), None);
+    }
+
+    #[test]
+    fn variants_are_kept_apart_by_vary() {
+        let cache = Arc::new(cache());
+        let gzip = headers(&[("accept-encoding", "gzip")]);
+        let key = cache.variant_key(
+            "GET host/path".to_string(),
+            &headers(&[("vary", "Accept-Encoding")]),
+            &gzip,
+        );
+        store(&cache, key, headers(&[("content-encoding", "gzip")]));
+        assert!(matches!(
+            cache.lookup("GET host/path", &gzip),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup("GET host/path", &headers(&[])),
+            Lookup::Miss
+        ));
+        assert!(matches!(
+            cache.lookup("GET host/path", &headers(&[("accept-encoding", "br")])),
+            Lookup::Miss
+        ));
+    }
+
+    #[test]
+    fn responses_without_vary_are_shared() {
+        let cache = Arc::new(cache());
+        let key = cache.variant_key("GET host/path".to_string(), &headers(&[]), &headers(&[]));
+        store(&cache, key, headers(&[]));
+        let other = headers(&[("accept-language", "de")]);
+        assert!(matches!(
+            cache.lookup("GET host/path", &other),
+            Lookup::Fresh(_)
+        ));
+    }
+
+    #[test]
+    fn the_oldest_variant_is_evicted() {
+        let cache = Arc::new(cache());
+        let vary = headers(&[("vary", "Accept-Encoding")]);
+        let encodings = [
+            headers(&[("accept-encoding", "gzip")]),
+            headers(&[("accept-encoding", "br")]),
+            headers(&[("accept-encoding", "zstd")]),
+        ];
+        for request in &encodings {
+            let key = cache.variant_key("GET host/path".to_string(), &vary, request);
+            store(&cache, key, headers(&[]));
+        }
+        assert!(matches!(
+            cache.lookup("GET host/path", &encodings[0]),
+            Lookup::Miss
+        ));
+        for request in &encodings[1..] {
+            assert!(matches!(
+                cache.lookup("GET host/path", request),
+                Lookup::Fresh(_)
+            ));
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use axum::{
+    body::Body,
+    http::{header, Response, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::conditions::host_without_port;
+
+/// Which form of a domain requests are redirected to.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CanonicalForm {
+    Www,
+    Apex,
+}
+
+/// Answers requests for the non-canonical form of a domain, keyed by apex
+/// domain, with a permanent redirect to the canonical one.
+pub fn redirect(
+    domains: &HashMap<String, CanonicalForm>,
+    host: &str,
+    path_and_query: &str,
+) -> Option<Response<Body>> {
+    let name = host_without_port(host).to_lowercase();
+    let port = &host[host_without_port(host).len()..];
+    let canonical = match name.strip_prefix("www.") {
+        Some(apex) if domains.get(apex) == Some(&CanonicalForm::Apex) => format!("{apex}{port}"),
+        None if domains.get(&name) == Some(&CanonicalForm::Www) => format!("www.{name}{port}"),
+        _ => return None,
+    };
+    // a scheme relative location keeps whatever scheme the client used
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, format!("//{canonical}{path_and_query}"))
+        .body(Body::empty())
+        .ok()
+}
@@ -0,0 +1,160 @@
+use axum::http::{header, HeaderMap, Uri};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// Networks of reverse proxies whose forwarded headers are believed. Entries
+/// are CIDR ranges or single addresses; only loopback is trusted by default.
+#[derive(Serialize, Clone, Debug)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl Default for TrustedProxies {
+    fn default() -> Self {
+        TrustedProxies(vec![
+            "127.0.0.0/8".parse().unwrap(),
+            "::1/128".parse().unwrap(),
+        ])
+    }
+}
+
+impl<'de> Deserialize<'de> for TrustedProxies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|entry| parse_network(entry).map_err(serde::de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+/// Parses a CIDR range or a single address.
+pub fn parse_network(entry: &str) -> Result<IpNet, String> {
+    entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid network `{entry}`"))
+}
+
+impl TrustedProxies {
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Where a request came from: the connected peer, and the client address
+/// resolved behind it.
+#[derive(Clone, Copy)]
+pub struct Remote {
+    pub peer: SocketAddr,
+    pub client: IpAddr,
+}
+
+/// Resolves the address of the client behind a request. Forwarded hops are
+/// walked from the nearest one outwards for as long as they were added by
+/// trusted proxies; the first untrusted address is the client. `Forwarded`
+/// takes precedence over `X-Forwarded-For`.
+pub fn resolve(peer: SocketAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> IpAddr {
+    let mut client = canonical(peer.ip());
+    if !trusted.contains(client) {
+        return client;
+    }
+    let hops = if headers.contains_key("forwarded") {
+        forwarded_hops(headers)
+    } else {
+        x_forwarded_for_hops(headers)
+    };
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = canonical(ip);
+                if !trusted.contains(client) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+/// Resolves the host a request is addressed to. `Forwarded` and
+/// `X-Forwarded-Host` are only believed from trusted proxies, so clients
+/// can't pick the host rules are matched against; otherwise the Host header
+/// is used, or the authority of an absolute-form uri.
+pub fn resolve_host(
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    uri: &Uri,
+    trusted: &TrustedProxies,
+) -> Option<String> {
+    let forwarded = || {
+        let element = values(headers, "forwarded").next()?;
+        element.split(';').find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("host")
+                .then(|| value.trim().trim_matches('"'))
+        })
+    };
+    let forwarded_host = || values(headers, "x-forwarded-host").next().map(str::trim);
+    let host = || headers.get(header::HOST)?.to_str().ok();
+    let from_proxy = trusted.contains(canonical(peer.ip()));
+    from_proxy
+        .then(|| forwarded().or_else(forwarded_host))
+        .flatten()
+        .or_else(host)
+        .or_else(|| uri.authority().map(|authority| authority.as_str()))
+        .map(str::to_string)
+}
+
+/// The X-Forwarded-For value sent upstream: the peer's address, appended to
+/// the hops a trusted proxy already put there.
+pub fn forwarded_for(peer: SocketAddr, headers: &HeaderMap, trusted: &TrustedProxies) -> String {
+    let peer_ip = canonical(peer.ip());
+    let hops: Vec<_> = values(headers, "x-forwarded-for")
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    if hops.is_empty() || !trusted.contains(peer_ip) {
+        return peer_ip.to_string();
+    }
+    format!("{}, {peer_ip}", hops.join(", "))
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+fn values<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+}
+
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    values(headers, "x-forwarded-for")
+        .map(|hop| hop.trim().parse().ok())
+        .collect()
+}
+
+/// Extracts the `for=` node of every RFC 7239 element; obfuscated or
+/// unknown nodes yield `None`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    values(headers, "forwarded")
+        .map(|element| {
+            let node = element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then_some(value)
+            })?;
+            let node = node.trim_matches('"');
+            match node.strip_prefix('[') {
+                Some(v6) => v6.split(']').next()?.parse().ok(),
+                None => node.split(':').next()?.parse().ok(),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,100 @@
+use axum::http::{header, HeaderMap, HeaderName};
+use ipnet::IpNet;
+use regex::Regex;
+use std::net::IpAddr;
+
+/// Request properties a rule requires besides its url pattern.
+#[derive(Default)]
+pub struct Conditions {
+    /// lowercased host names the request must be for, either exact names or
+    /// `*.domain` wildcards; empty allows any host
+    pub hosts: Vec<String>,
+    /// cookie names with the pattern their value must match, `None` if the
+    /// cookie only has to be present
+    pub cookies: Vec<(String, Option<Regex>)>,
+    /// content negotiation headers with the pattern one of their entries must
+    /// match
+    pub negotiation: Vec<(HeaderName, Regex)>,
+    /// networks the client address must be in; empty allows any client
+    pub clients: Vec<IpNet>,
+}
+
+impl Conditions {
+    pub fn matches(&self, host: &str, headers: &HeaderMap, client: IpAddr) -> bool {
+        if !self.clients.is_empty() && !self.clients.iter().any(|net| net.contains(&client)) {
+            return false;
+        }
+        let host = host_without_port(host);
+        let hosts =
+            self.hosts.is_empty() || self.hosts.iter().any(|pattern| host_matches(pattern, host));
+        let cookies = self
+            .cookies
+            .iter()
+            .all(|(name, pattern)| match cookie(headers, name) {
+                Some(value) => pattern.as_ref().is_none_or(|re| re.is_match(value)),
+                None => false,
+            });
+        hosts
+            && cookies
+            && self
+                .negotiation
+                .iter()
+                .all(|(name, pattern)| entries(headers, name).any(|entry| pattern.is_match(&entry)))
+    }
+}
+
+/// Whether `host` is `pattern` or, for a `*.domain` pattern, a subdomain of
+/// `domain`.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            let split = host.len().saturating_sub(domain.len());
+            split > 1
+                && host.as_bytes()[split - 1] == b'.'
+                && host
+                    .get(split..)
+                    .is_some_and(|tail| tail.eq_ignore_ascii_case(domain))
+        }
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// `host` as found in a Host header, without its port.
+pub fn host_without_port(host: &str) -> &str {
+    host.rsplit_once(':')
+        .filter(|(_, port)| port.bytes().all(|b| b.is_ascii_digit()))
+        .map_or(host, |(host, _)| host)
+}
+
+/// The value of the first cookie called `name` across all Cookie headers.
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// The lowercased entries of a list header like Accept without their
+/// parameters, leaving out those the client refuses with `q=0`.
+fn entries<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = String> + 'a {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let value = parts.next().filter(|value| !value.is_empty())?;
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (!refused).then(|| value.to_lowercase())
+        })
+}
@@ -0,0 +1,184 @@
+use hyper::{
+    client::{
+        connect::{dns::Name, Connected, Connection},
+        HttpConnector,
+    },
+    Uri,
+};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use rustls::{client::ServerCertVerifier, ClientConfig, ServerName};
+use std::{
+    future::Future,
+    io,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{errors, timing};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// The upstream TLS configuration checking certificates with `verifier` and
+/// offering `alpn`.
+pub fn tls_config(verifier: Arc<dyn ServerCertVerifier>, alpn: &[&[u8]]) -> ClientConfig {
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    config
+}
+
+/// Connects to the TCP targets of a rule, through TLS for `https` ones, and
+/// marks the end of the connect and the span of the handshake on the timeline
+/// of the exchange running in the task.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<Resolver>,
+    tls: TlsConnector,
+    /// covers the handshake as well as the TCP connect
+    timeout: Option<Duration>,
+}
+
+impl Connector {
+    pub fn new(
+        resolver: Arc<dyn Resolve>,
+        tls: ClientConfig,
+        local_address: Option<IpAddr>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let mut http = HttpConnector::new_with_resolver(Resolver(resolver));
+        http.enforce_http(false);
+        http.set_nodelay(true);
+        http.set_local_address(local_address);
+        Connector {
+            http,
+            tls: Arc::new(tls).into(),
+            timeout,
+        }
+    }
+}
+
+impl tower::Service<Uri> for Connector {
+    type Response = Stream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Stream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri.clone());
+        let (tls, timeout) = (self.tls.clone(), self.timeout);
+        let connect = async move {
+            let tcp = connecting.await?;
+            timing::connected();
+            if uri.scheme_str() != Some("https") {
+                return Ok::<_, Error>(Stream::Plain(tcp));
+            }
+            let host = uri.host().unwrap_or_default();
+            let server_name = ServerName::try_from(host.trim_matches(['[', ']']))?;
+            timing::handshaking();
+            let tls = tls.connect(server_name, tcp).await?;
+            timing::secured();
+            Ok(Stream::Tls(Box::new(tls)))
+        };
+        Box::pin(async move {
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect)
+                    .await
+                    .map_err(|_| errors::ConnectTimeout(timeout))?,
+                None => connect.await,
+            }
+        })
+    }
+}
+
+/// A connection to a TCP target, as hyper's pool takes it.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection for Stream {
+    fn connected(&self) -> Connected {
+        match self {
+            Stream::Plain(tcp) => tcp.connected(),
+            Stream::Tls(tls) => {
+                let (tcp, session) = tls.get_ref();
+                if session.alpn_protocol() == Some(b"h2") {
+                    tcp.connected().negotiated_h2()
+                } else {
+                    tcp.connected()
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            Stream::Tls(tls) => Pin::new(tls).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            Stream::Tls(tls) => Pin::new(tls).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            Stream::Tls(tls) => Pin::new(tls).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            Stream::Tls(tls) => Pin::new(tls).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Lets hyper's connector look names up with a reqwest resolver.
+#[derive(Clone)]
+struct Resolver(Arc<dyn Resolve>);
+
+impl tower::Service<Name> for Resolver {
+    type Response = Addrs;
+    type Error = Error;
+    type Future = Resolving;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Resolving {
+        self.0.resolve(name)
+    }
+}
@@ -0,0 +1,123 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResponseContentTypeConfig {
+    /// sent instead of whatever Content-Type the upstream claims
+    #[serde(default)]
+    pub force: Option<String>,
+    /// media types passed through, such as `text/html` or `image/*`; others
+    /// are replaced by `default`. Empty allows all valid ones.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+    /// sent when the upstream Content-Type is missing, malformed or not
+    /// allowed, which is otherwise dropped
+    #[serde(default)]
+    pub default: Option<String>,
+    /// adds `X-Content-Type-Options: nosniff`, so browsers stick to the
+    /// Content-Type instead of guessing from the body
+    #[serde(default = "default_nosniff")]
+    pub nosniff: bool,
+}
+
+fn default_nosniff() -> bool {
+    true
+}
+
+/// Overrides and sanitizes the Content-Type of upstream responses.
+pub struct ResponseContentType {
+    force: Option<HeaderValue>,
+    /// lowercased `type/subtype`, or `type/*`
+    allowed: Vec<String>,
+    default: Option<HeaderValue>,
+    nosniff: bool,
+}
+
+impl ResponseContentType {
+    pub fn new(config: &ResponseContentTypeConfig) -> anyhow::Result<Self> {
+        let value = |option: &str, value: &String| {
+            media_type(value)
+                .and_then(|_| HeaderValue::from_str(value).ok())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("`response_content_type.{option}` `{value}` is not a media type")
+                })
+        };
+        let allowed = config
+            .allowed
+            .iter()
+            .map(|allowed| match allowed.split_once('/') {
+                Some((kind, "*")) if is_token(kind) => Ok(allowed.to_ascii_lowercase()),
+                _ => media_type(allowed).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "`response_content_type.allowed` `{allowed}` is not a media type"
+                    )
+                }),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ResponseContentType {
+            force: config
+                .force
+                .as_ref()
+                .map(|force| value("force", force))
+                .transpose()?,
+            allowed,
+            default: config
+                .default
+                .as_ref()
+                .map(|default| value("default", default))
+                .transpose()?,
+            nosniff: config.nosniff,
+        })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if self.nosniff {
+            headers.insert(
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            );
+        }
+        if let Some(force) = &self.force {
+            headers.insert(header::CONTENT_TYPE, force.clone());
+            return;
+        }
+        let mut values = headers.get_all(header::CONTENT_TYPE).iter();
+        let accepted = match (values.next(), values.next()) {
+            (Some(value), None) => value
+                .to_str()
+                .ok()
+                .and_then(media_type)
+                .is_some_and(|media_type| self.allows(&media_type)),
+            _ => false,
+        };
+        if accepted {
+            return;
+        }
+        match &self.default {
+            Some(default) => headers.insert(header::CONTENT_TYPE, default.clone()),
+            None => headers.remove(header::CONTENT_TYPE),
+        };
+    }
+
+    fn allows(&self, media_type: &str) -> bool {
+        self.allowed.is_empty()
+            || self.allowed.iter().any(|allowed| match allowed.strip_suffix('*') {
+                Some(kind) => media_type.starts_with(kind),
+                None => allowed == media_type,
+            })
+    }
+}
+
+/// The lowercased `type/subtype` of a Content-Type value, if well-formed.
+fn media_type(value: &str) -> Option<String> {
+    let essence = value.split(';').next()?.trim();
+    let (kind, subtype) = essence.split_once('/')?;
+    (is_token(kind) && is_token(subtype)).then(|| essence.to_ascii_lowercase())
+}
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
@@ -0,0 +1,141 @@
+use axum::http::{header, HeaderMap, HeaderValue};
+use std::collections::HashMap;
+
+/// Rewrites the `Domain` and `Path` attributes of upstream `Set-Cookie`
+/// headers, so cookies set for the backend's names still reach it through
+/// the proxy.
+#[derive(Default)]
+pub struct CookieRewrite {
+    /// lowercased upstream domains without a leading dot, and their
+    /// replacement; an empty one drops the attribute, making the cookie
+    /// host-only
+    domains: Vec<(String, String)>,
+    /// upstream path prefixes, longest first, and what replaces them
+    paths: Vec<(String, String)>,
+}
+
+impl CookieRewrite {
+    pub fn new(domains: &HashMap<String, String>, paths: &HashMap<String, String>) -> Self {
+        let mut paths: Vec<_> = paths
+            .iter()
+            .map(|(from, to)| (from.clone(), to.clone()))
+            .collect();
+        paths.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+        CookieRewrite {
+            domains: domains
+                .iter()
+                .map(|(from, to)| (from.trim_start_matches('.').to_lowercase(), to.clone()))
+                .collect(),
+            paths,
+        }
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        if self.domains.is_empty() && self.paths.is_empty() {
+            return Ok(());
+        }
+        let header::Entry::Occupied(entry) = headers.entry(header::SET_COOKIE) else {
+            return Ok(());
+        };
+        let (_, cookies) = entry.remove_entry_mult();
+        let cookies: Vec<_> = cookies.collect();
+        for cookie in cookies {
+            let value = match cookie.to_str() {
+                Ok(value) => HeaderValue::from_str(&self.rewrite(value))?,
+                Err(_) => cookie,
+            };
+            headers.append(header::SET_COOKIE, value);
+        }
+        Ok(())
+    }
+
+    fn rewrite(&self, cookie: &str) -> String {
+        let mut attributes = cookie.split(';');
+        let mut rewritten = attributes.next().unwrap_or_default().to_string();
+        for attribute in attributes {
+            let attribute = attribute.trim();
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            let replaced = if name.trim().eq_ignore_ascii_case("domain") {
+                self.domains
+                    .iter()
+                    .find(|(from, _)| value.trim_start_matches('.').eq_ignore_ascii_case(from))
+                    .map(|(_, to)| to.clone())
+            } else if name.trim().eq_ignore_ascii_case("path") {
+                self.paths
+                    .iter()
+                    .find(|(from, _)| value.starts_with(from.as_str()))
+                    .map(|(from, to)| match format!("{to}{}", &value[from.len()..]) {
+                        path if path.is_empty() => "/".to_string(),
+                        path => path,
+                    })
+            } else {
+                None
+            };
+            match replaced {
+                Some(replaced) if replaced.is_empty() => {}
+                Some(replaced) => rewritten += &format!("; {}={replaced}", name.trim()),
+                None => rewritten += &format!("; {attribute}"),
+            }
+        }
+        rewritten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(cookies: &[&str]) -> Vec<String> {
+        let domains = HashMap::from([
+            (".backend.internal".to_string(), "example.com".to_string()),
+            ("hostonly.internal".to_string(), String::new()),
+        ]);
+        let paths = HashMap::from([
+            ("/app".to_string(), String::new()),
+            ("/app/api".to_string(), "/api".to_string()),
+        ]);
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(header::SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+        }
+        CookieRewrite::new(&domains, &paths)
+            .apply(&mut headers)
+            .unwrap();
+        headers
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_domains_and_paths() {
+        assert_eq!(
+            rewrite(&[
+                "a=1; Domain=Backend.Internal; Path=/app/x; HttpOnly",
+                "b=2; domain=hostonly.internal; path=/app/api/v1",
+                "c=3; Domain=other.org; Path=/elsewhere",
+            ]),
+            [
+                "a=1; Domain=example.com; Path=/x; HttpOnly",
+                "b=2; path=/api/v1",
+                "c=3; Domain=other.org; Path=/elsewhere",
+            ]
+        );
+    }
+
+    #[test]
+    fn turns_an_emptied_path_into_the_root() {
+        let paths = HashMap::from([("/app".to_string(), String::new())]);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::SET_COOKIE,
+            HeaderValue::from_static("a=1; Path=/app"),
+        );
+        CookieRewrite::new(&HashMap::new(), &paths)
+            .apply(&mut headers)
+            .unwrap();
+        assert_eq!(headers[header::SET_COOKIE], "a=1; Path=/");
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+
+/// Detaches the process from its terminal with the usual double fork. Must be
+/// called before any threads are started, i.e. before the tokio runtime.
+#[cfg(unix)]
+pub fn daemonize() -> anyhow::Result<()> {
+    use std::{fs::OpenOptions, io, os::fd::AsRawFd};
+
+    fn fork() -> io::Result<()> {
+        match unsafe { libc::fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            _ => unsafe { libc::_exit(0) },
+        }
+    }
+
+    fork()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    fork()?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> anyhow::Result<()> {
+    anyhow::bail!("--daemon is only supported on unix")
+}
+
+/// A pid file removed again when dropped.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: &str) -> anyhow::Result<Self> {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile(PathBuf::from(path)))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// A file listing the URLs listened on, removed again when dropped. It is
+/// renamed into place, so a reader never sees it half written.
+pub struct PortFile(PathBuf);
+
+impl PortFile {
+    pub fn create(path: &str, urls: &[String]) -> anyhow::Result<Self> {
+        let partial = format!("{path}.partial");
+        std::fs::write(
+            &partial,
+            urls.iter()
+                .map(|url| format!("{url}\n"))
+                .collect::<String>(),
+        )?;
+        std::fs::rename(&partial, path)?;
+        Ok(PortFile(PathBuf::from(path)))
+    }
+}
+
+impl Drop for PortFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Switches to an unprivileged user and group once the listeners are bound.
+/// Names are looked up in the system databases, numeric ids are used as is;
+/// `group` defaults to the primary group of `user`, which a numeric id without
+/// a passwd entry does not have.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    use std::{ffi::CString, io};
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    let user = match user {
+        Some(user) => {
+            let name = CString::new(user)?;
+            let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+            if !passwd.is_null() {
+                Some(unsafe { (name, (*passwd).pw_uid, Some((*passwd).pw_gid)) })
+            } else if let Ok(uid) = user.parse::<libc::uid_t>() {
+                Some((name, uid, None))
+            } else {
+                anyhow::bail!("unknown user `{user}`");
+            }
+        }
+        None => None,
+    };
+    let gid = match group {
+        Some(group) => {
+            let name = CString::new(group)?;
+            let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+            if !entry.is_null() {
+                Some(unsafe { (*entry).gr_gid })
+            } else if let Ok(gid) = group.parse::<libc::gid_t>() {
+                Some(gid)
+            } else {
+                anyhow::bail!("unknown group `{group}`");
+            }
+        }
+        None => match &user {
+            Some((_, _, Some(gid))) => Some(*gid),
+            // keeping the groups of root would undo most of the drop
+            Some((_, uid, None)) => {
+                anyhow::bail!("user {uid} has no passwd entry, --group is required")
+            }
+            None => None,
+        },
+    };
+
+    if let Some(gid) = gid {
+        match &user {
+            Some((name, _, _)) => check(unsafe { libc::initgroups(name.as_ptr(), gid as _) })?,
+            None => check(unsafe { libc::setgroups(1, &gid) })?,
+        }
+        check(unsafe { libc::setgid(gid) })?;
+    }
+    if let Some((_, uid, _)) = user {
+        check(unsafe { libc::setuid(uid) })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> anyhow::Result<()> {
+    anyhow::bail!("--user and --group are only supported on unix")
+}
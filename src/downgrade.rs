@@ -0,0 +1,38 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+
+/// Drops the hop-by-hop headers of the upstream response, which describe the
+/// upstream connection and, once passed to an HTTP/1.0 client, make it expect
+/// chunked bodies or a persistent connection the proxy won't provide. HTTP/3
+/// forbids them altogether.
+pub fn strip_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in listed {
+        headers.remove(name.as_str());
+    }
+    headers.remove(header::CONNECTION);
+    headers.remove("keep-alive");
+    headers.remove(header::TRANSFER_ENCODING);
+}
+
+/// Whether a response with `status` and `headers` to a `method` request
+/// carries a body of unknown length, which an HTTP/1.0 client can only read
+/// to the end of the connection.
+pub fn needs_length(method: &Method, status: StatusCode, headers: &HeaderMap) -> bool {
+    method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+        && !headers.contains_key(header::CONTENT_LENGTH)
+}
+
+/// Marks a response whose body couldn't be given a length as ending with the
+/// connection.
+pub fn close(headers: &mut HeaderMap) {
+    headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+}
@@ -0,0 +1,176 @@
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+use crate::{balance::BalanceStrategy, ProxyItem};
+
+/// An upstream target template. Targets are shared between the routing tables
+/// of consecutive reloads, so requests already running against a target can
+/// be cut off once it has been removed and its drain period has elapsed.
+pub struct Target {
+    pub template: String,
+    cutoff: watch::Sender<bool>,
+    /// cleared while health checks fail, kept across reloads
+    healthy: AtomicBool,
+}
+
+impl Target {
+    pub fn new(template: String) -> Arc<Self> {
+        Arc::new(Target {
+            template,
+            cutoff: watch::channel(false).0,
+            healthy: AtomicBool::new(true),
+        })
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.cutoff.subscribe()
+    }
+
+    fn retire_after(self: &Arc<Self>, period: Duration) {
+        let target = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(period).await;
+            target.cutoff.send_replace(true);
+            tracing::info!(target = target.template, "drained");
+        });
+    }
+}
+
+/// The target list of a previous config, still used for sticky clients
+/// until `until`.
+#[derive(Clone)]
+pub struct Draining {
+    pub targets: Vec<Arc<Target>>,
+    pub until: Instant,
+}
+
+/// Carries targets over from the routing table being replaced: unchanged
+/// targets are shared, removed ones are cut off after the drain period, and
+/// ip_hash rules keep mapping clients onto the old target list meanwhile.
+/// Lists still draining from earlier reloads are kept until their own end, so
+/// clients stay on the oldest one rather than being remapped by each reload.
+pub fn carry_over(old: &[ProxyItem], new: &mut [ProxyItem]) {
+    for old_item in old {
+        let mut new_item = new.iter_mut().find(|item| item.name == old_item.name);
+        let drain_period = new_item
+            .as_ref()
+            .map_or(old_item.drain_period, |item| item.drain_period);
+        let mut changed = false;
+        for old_target in old_item.targets.iter() {
+            let kept = new_item.as_mut().and_then(|item| {
+                item.targets
+                    .iter_mut()
+                    .find(|target| target.template == old_target.template)
+            });
+            match kept {
+                Some(target) => *target = old_target.clone(),
+                None => {
+                    changed = true;
+                    old_target.retire_after(drain_period);
+                }
+            }
+        }
+        let ip_hash = new_item.filter(|item| item.balancer.strategy() == BalanceStrategy::IpHash);
+        if let Some(item) = ip_hash {
+            let now = Instant::now();
+            item.draining = old_item
+                .draining
+                .iter()
+                .filter(|draining| now < draining.until)
+                .cloned()
+                .collect();
+            changed |= item.targets.len() != old_item.targets.len();
+            if changed {
+                item.draining.push(Draining {
+                    targets: old_item.targets.clone(),
+                    until: now + drain_period,
+                });
+            }
+        }
+    }
+}
+
+/// Forwards `body` until the target is cut off, then fails the stream so the
+/// client connection is aborted instead of seeing a truncated but complete body.
+pub fn until_cutoff(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    cutoff: watch::Receiver<bool>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static {
+    stream::unfold(Some((Box::pin(body), cutoff)), |state| async move {
+        let (mut body, mut cutoff) = state?;
+        let chunk = tokio::select! {
+            chunk = body.next() => chunk?.map_err(anyhow::Error::from),
+            _ = cutoff.wait_for(|retired| *retired) => Err(anyhow::anyhow!("target drained")),
+        };
+        match chunk {
+            Ok(chunk) => Some((Ok(chunk), Some((body, cutoff)))),
+            Err(err) => Some((Err(err), None)),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_config;
+
+    fn items(targets: &[&str]) -> Vec<ProxyItem> {
+        let yaml = format!(
+            "sticky:\n  match: \"^(.*)\"\n  targets: {targets:?}\n  balance: ip_hash\n  drain_period: 1m\n"
+        );
+        parse_config(&serde_yaml::from_str(&yaml).unwrap())
+            .unwrap()
+            .items
+    }
+
+    fn templates(draining: &Draining) -> Vec<&str> {
+        draining
+            .targets
+            .iter()
+            .map(|target| target.template.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_draining_lists_of_earlier_reloads() {
+        let first = items(&["http://a/", "http://b/"]);
+        let mut second = items(&["http://a/"]);
+        carry_over(&first, &mut second);
+        let mut third = items(&["http://c/"]);
+        carry_over(&second, &mut third);
+
+        let draining: Vec<_> = third[0].draining.iter().map(templates).collect();
+        assert_eq!(
+            draining,
+            [vec!["http://a/", "http://b/"], vec!["http://a/"]]
+        );
+        let mut fourth = items(&["http://c/"]);
+        carry_over(&third, &mut fourth);
+        assert_eq!(fourth[0].draining.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn shares_unchanged_targets() {
+        let first = items(&["http://a/", "http://b/"]);
+        let mut second = items(&["http://a/", "http://b/"]);
+        carry_over(&first, &mut second);
+        assert!(Arc::ptr_eq(&first[0].targets[0], &second[0].targets[0]));
+        assert!(second[0].draining.is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+
+/// How error responses generated by reproxy itself are rendered.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorFormat {
+    #[default]
+    Empty,
+    Json,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    code: &'a str,
+    message: &'a str,
+    request_id: &'a str,
+    rule: Option<&'a str>,
+}
+
+/// Builds a proxy-generated error response. `code` is a stable machine
+/// readable identifier, `message` a human readable explanation.
+pub fn respond(
+    format: ErrorFormat,
+    status: StatusCode,
+    code: &str,
+    message: &str,
+    request_id: &str,
+    rule: Option<&str>,
+) -> Response<Body> {
+    let builder = Response::builder().status(status);
+    match format {
+        ErrorFormat::Empty => builder.body(Body::empty()),
+        ErrorFormat::Json => {
+            let body = serde_json::to_vec(&ErrorBody {
+                status: status.as_u16(),
+                code,
+                message,
+                request_id,
+                rule,
+            })
+            .unwrap();
+            builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+        }
+    }
+    .unwrap()
+}
+
+/// Returns the request id sent by the client in `X-Request-Id`, or generates
+/// a new one.
+pub fn request_id(headers: &HeaderMap) -> String {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    if let Some(id) = headers.get("x-request-id").and_then(|v| v.to_str().ok()) {
+        return id.to_string();
+    }
+    let seed = *SEED.get_or_init(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+    });
+    format!("{:016x}", seed ^ NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Raised when the upstream didn't send its response head within the rule's
+/// `first_byte_timeout`.
+#[derive(Debug)]
+pub struct FirstByteTimeout(pub std::time::Duration);
+
+impl std::fmt::Display for FirstByteTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no response head within {}",
+            humantime::format_duration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for FirstByteTimeout {}
+
+/// Raised when the upstream sent no part of its response body within the
+/// rule's `read_timeout`.
+#[derive(Debug)]
+pub struct ReadTimeout(pub std::time::Duration);
+
+impl std::fmt::Display for ReadTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no response data within {}",
+            humantime::format_duration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for ReadTimeout {}
+
+/// Raised when a Unix socket upstream didn't accept the connection within
+/// the rule's `connect_timeout`; reqwest raises its own for TCP upstreams.
+#[derive(Debug)]
+pub struct ConnectTimeout(pub std::time::Duration);
+
+impl std::fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "not connected within {}",
+            humantime::format_duration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for ConnectTimeout {}
+
+/// Raised when a Unix socket upstream didn't complete its response within
+/// the rule's `request_timeout`; reqwest raises its own for TCP upstreams.
+#[derive(Debug)]
+pub struct RequestTimeout(pub std::time::Duration);
+
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no complete response within {}",
+            humantime::format_duration(self.0)
+        )
+    }
+}
+
+impl std::error::Error for RequestTimeout {}
+
+/// Raised when the upstream response head exceeds `max_response_header_size`.
+#[derive(Debug)]
+pub struct HeadersTooLarge(pub usize);
+
+impl std::fmt::Display for HeadersTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response headers of {} bytes exceed the limit", self.0)
+    }
+}
+
+impl std::error::Error for HeadersTooLarge {}
+
+/// Raised when an upstream response body exceeds `max_response_size`.
+#[derive(Debug)]
+pub struct ResponseTooLarge(pub usize);
+
+impl std::fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response body exceeds the limit of {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+/// Raised when an upstream response header fails a `Replace` response header
+/// action that rejects mismatches.
+#[derive(Debug)]
+pub struct HeaderMismatch(pub String);
+
+impl std::fmt::Display for HeaderMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response header `{}` does not match", self.0)
+    }
+}
+
+impl std::error::Error for HeaderMismatch {}
+
+/// Maps a failed upstream attempt to the status, code and message of the
+/// error response sent to the client.
+pub fn classify(err: &anyhow::Error) -> (StatusCode, &'static str, &'static str) {
+    let caused_by = |check: fn(&(dyn std::error::Error + 'static)) -> bool| err.chain().any(check);
+    if caused_by(|cause| cause.is::<crate::ssrf::InternalTarget>()) {
+        (
+            StatusCode::FORBIDDEN,
+            "internal_target",
+            "the target resolves to an internal address",
+        )
+    } else if caused_by(|cause| cause.is::<crate::scheme::InsecureTarget>()) {
+        (
+            StatusCode::BAD_GATEWAY,
+            "insecure_target",
+            "the target does not use https",
+        )
+    } else if caused_by(|cause| cause.is::<crate::authority::InvalidTarget>()) {
+        (
+            StatusCode::BAD_GATEWAY,
+            "invalid_target",
+            "the rewritten target is not a valid url",
+        )
+    } else if caused_by(|cause| cause.is::<HeaderMismatch>()) {
+        (
+            StatusCode::BAD_GATEWAY,
+            "upstream_header_mismatch",
+            "an upstream response header does not match the expected pattern",
+        )
+    } else if caused_by(|cause| cause.is::<HeadersTooLarge>()) {
+        (
+            StatusCode::BAD_GATEWAY,
+            "upstream_headers_too_large",
+            "the upstream response headers are too large",
+        )
+    } else if caused_by(|cause| cause.is::<ResponseTooLarge>()) {
+        (
+            StatusCode::BAD_GATEWAY,
+            "upstream_response_too_large",
+            "the upstream response is too large",
+        )
+    } else if caused_by(|cause| {
+        cause.is::<FirstByteTimeout>()
+            || cause.is::<ReadTimeout>()
+            || cause.is::<ConnectTimeout>()
+            || cause.is::<RequestTimeout>()
+    }) {
+        (
+            StatusCode::GATEWAY_TIMEOUT,
+            "upstream_timeout",
+            "the upstream did not respond in time",
+        )
+    } else {
+        (
+            StatusCode::BAD_GATEWAY,
+            "upstream_unavailable",
+            "the upstream could not be reached",
+        )
+    }
+}
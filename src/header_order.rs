@@ -0,0 +1,64 @@
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Order of the headers sent upstream, for upstreams that fingerprint it.
+/// Headers the HTTP client adds itself, such as a default `User-Agent`, come
+/// last either way.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderOrderConfig {
+    /// the order the client sent them in, followed by those the proxy adds
+    Client,
+    /// the listed headers first, in this order, followed by the others
+    Fixed(Vec<String>),
+}
+
+pub enum HeaderOrder {
+    Client,
+    Fixed(Vec<HeaderName>),
+}
+
+impl HeaderOrder {
+    pub fn new(config: &HeaderOrderConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            HeaderOrderConfig::Client => HeaderOrder::Client,
+            HeaderOrderConfig::Fixed(names) => HeaderOrder::Fixed(
+                names
+                    .iter()
+                    .map(|name| {
+                        HeaderName::try_from(name.as_str()).map_err(|_| {
+                            anyhow::anyhow!("`header_order` has an invalid header name `{name}`")
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+        })
+    }
+
+    /// Reorders the `headers` of a request to `url`; `client` are those of
+    /// the client request. `Host` is added when missing so it can be placed
+    /// too, instead of being appended by the HTTP client.
+    pub fn apply(&self, headers: &mut HeaderMap, client: &HeaderMap, url: &reqwest::Url) {
+        if !headers.contains_key(header::HOST) {
+            let authority = &url[url::Position::BeforeHost..url::Position::AfterPort];
+            if let Ok(value) = HeaderValue::from_str(authority) {
+                headers.insert(header::HOST, value);
+            }
+        }
+        let first: Vec<&HeaderName> = match self {
+            HeaderOrder::Client => client.keys().collect(),
+            HeaderOrder::Fixed(names) => names.iter().collect(),
+        };
+        let mut ordered = HeaderMap::with_capacity(headers.len());
+        let mut placed = HashSet::new();
+        for name in first.into_iter().chain(headers.keys()) {
+            if placed.insert(name.clone()) {
+                for value in headers.get_all(name) {
+                    ordered.append(name.clone(), value.clone());
+                }
+            }
+        }
+        *headers = ordered;
+    }
+}
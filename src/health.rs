@@ -0,0 +1,105 @@
+use axum::http::Method;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::task::JoinSet;
+
+use crate::{probe, ProxyTable};
+
+/// Requests sent to every target of a rule in the background, taking targets
+/// that keep failing out of rotation until they pass again.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    /// path requested on the target; 2xx and 3xx responses count as healthy
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+    /// consecutive passed checks after which a down target is used again
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    /// consecutive failed checks after which a target is taken out
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+/// The url checked for `template`, if its scheme and authority don't depend
+/// on capture groups.
+pub fn check_url(template: &str, path: &str) -> Option<reqwest::Url> {
+    probe::static_authority(template)?;
+    let literal = template.split('$').next()?;
+    reqwest::Url::parse(literal).ok()?.join(path).ok()
+}
+
+/// Starts checking the targets of every rule with a `health_check`. The
+/// checks run until the returned set is dropped, which happens when the
+/// table is replaced on reload.
+pub fn spawn(table: &Arc<ProxyTable>) -> JoinSet<()> {
+    let mut checks = JoinSet::new();
+    for (index, item) in table.items.iter().enumerate() {
+        let Some(config) = &item.health_check else {
+            continue;
+        };
+        for target in item.targets.iter() {
+            let Some(url) = check_url(&target.template, &config.path) else {
+                continue;
+            };
+            let (table, target) = (table.clone(), target.clone());
+            checks.spawn(async move {
+                let item = &table.items[index];
+                let config = item.health_check.as_ref().unwrap();
+                let mut interval = tokio::time::interval(config.interval);
+                let (mut passed, mut failed) = (0, 0);
+                loop {
+                    interval.tick().await;
+                    let client = item.client.get();
+                    let check = client.execute(reqwest::Request::new(Method::GET, url.clone()));
+                    let result = match tokio::time::timeout(config.timeout, check).await {
+                        Ok(Ok(response)) if response.status().as_u16() < 400 => Ok(()),
+                        Ok(Ok(response)) => Err(format!("status {}", response.status().as_u16())),
+                        Ok(Err(err)) => Err(err.to_string()),
+                        Err(_) => Err("timed out".to_string()),
+                    };
+                    match result {
+                        Ok(()) => (passed, failed) = (passed + 1, 0),
+                        Err(_) => (passed, failed) = (0, failed + 1),
+                    }
+                    if target.is_healthy() && failed >= config.unhealthy_threshold {
+                        target.set_healthy(false);
+                        tracing::warn!(
+                            rule = item.name,
+                            target = target.template,
+                            error = result.err(),
+                            "target down"
+                        );
+                    } else if !target.is_healthy() && passed >= config.healthy_threshold {
+                        target.set_healthy(true);
+                        tracing::info!(rule = item.name, target = target.template, "target up");
+                    }
+                }
+            });
+        }
+    }
+    checks
+}
@@ -0,0 +1,32 @@
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::time::Duration;
+
+/// Sends an SSE comment line whenever the upstream event stream has been
+/// quiet for `interval`, so buffering intermediaries don't time the client
+/// out. Comments are only inserted at line boundaries, where clients ignore
+/// them.
+pub fn inject(
+    body: impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static,
+    interval: Duration,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static {
+    stream::unfold(
+        (Box::pin(body), true),
+        move |(mut body, at_line_start)| async move {
+            let next = if at_line_start {
+                match tokio::time::timeout(interval, body.next()).await {
+                    Ok(next) => next?,
+                    Err(_) => return Some((Ok(Bytes::from_static(b":\n")), (body, true))),
+                }
+            } else {
+                body.next().await?
+            };
+            let at_line_start = match &next {
+                Ok(chunk) if chunk.is_empty() => at_line_start,
+                Ok(chunk) => chunk.ends_with(b"\n"),
+                Err(_) => true,
+            };
+            Some((next, (body, at_line_start)))
+        },
+    )
+}
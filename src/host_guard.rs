@@ -0,0 +1,54 @@
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+
+use crate::conditions::{host_matches, host_without_port};
+
+/// Checks the host of a request before it is matched against any rule.
+#[derive(Default)]
+pub struct HostGuard {
+    /// exact names or `*.domain` wildcards; empty allows any host
+    pub allowed_hosts: Vec<String>,
+    /// refuses absolute-form requests whose uri names another host than the
+    /// Host header
+    pub reject_authority_mismatch: bool,
+}
+
+impl HostGuard {
+    /// The status, code and message to refuse a request for `host` with.
+    pub fn check(
+        &self,
+        host: &str,
+        uri: &Uri,
+        headers: &HeaderMap,
+    ) -> Option<(StatusCode, &'static str, &'static str)> {
+        if self.reject_authority_mismatch {
+            let header_host = headers
+                .get(header::HOST)
+                .and_then(|value| value.to_str().ok());
+            if let (Some(authority), Some(header_host)) = (uri.authority(), header_host) {
+                if !host_without_port(authority.as_str())
+                    .eq_ignore_ascii_case(host_without_port(header_host))
+                {
+                    return Some((
+                        StatusCode::BAD_REQUEST,
+                        "authority_mismatch",
+                        "the request uri and the Host header name different hosts",
+                    ));
+                }
+            }
+        }
+        let name = host_without_port(host);
+        if !self.allowed_hosts.is_empty()
+            && !self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| host_matches(allowed, name))
+        {
+            return Some((
+                StatusCode::MISDIRECTED_REQUEST,
+                "host_not_allowed",
+                "the requested host is not served here",
+            ));
+        }
+        None
+    }
+}
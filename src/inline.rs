@@ -0,0 +1,46 @@
+use serde_yaml::{Mapping, Value};
+
+use crate::Config;
+
+/// Builds a config from rules given on the command line, each written as
+/// `REGEX=>TARGET [FLAG...]` with space separated flags:
+///
+/// - `follow` follows upstream redirects
+/// - `+NAME` passes the client's `NAME` header through, `+*` passes all
+pub fn parse(rules: &[String]) -> anyhow::Result<Config> {
+    let mut config = Mapping::new();
+    for (index, rule) in rules.iter().enumerate() {
+        let Some((pattern, rest)) = rule.split_once("=>") else {
+            anyhow::bail!("rule `{rule}` is not of the form `REGEX=>TARGET`");
+        };
+        let mut words = rest.split_whitespace();
+        let Some(target) = words.next() else {
+            anyhow::bail!("rule `{rule}` has no target");
+        };
+        let mut item = Mapping::new();
+        let mut headers = Mapping::new();
+        item.insert("match".into(), pattern.trim().into());
+        item.insert("target".into(), target.into());
+        for flag in words {
+            match flag {
+                "follow" => {
+                    item.insert("follow_redirect".into(), true.into());
+                }
+                "+*" => {
+                    headers.insert("$default".into(), Value::Null);
+                }
+                _ => match flag.strip_prefix('+') {
+                    Some(name) if !name.is_empty() => {
+                        headers.insert(name.into(), Value::Null);
+                    }
+                    _ => anyhow::bail!("rule `{rule}` has an unknown flag `{flag}`"),
+                },
+            }
+        }
+        if !headers.is_empty() {
+            item.insert("headers".into(), headers.into());
+        }
+        config.insert(format!("rule{}", index + 1).into(), item.into());
+    }
+    Ok(serde_yaml::from_value(config.into())?)
+}
@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+/// Environment variables overriding the labels of the config.
+const ENVIRONMENT_ENV: &str = "REPROXY_ENVIRONMENT";
+const INSTANCE_ENV: &str = "REPROXY_INSTANCE";
+
+static LABELS: OnceLock<Labels> = OnceLock::new();
+
+/// Labels telling instances apart where their logs, reports and alerts are
+/// aggregated, such as `environment: staging`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Labels {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+}
+
+impl Labels {
+    /// The labels set, as name and value.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("environment", self.environment.as_deref()),
+            ("instance", self.instance.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
+
+/// Sets the labels of the process from `environment` and `instance` of the
+/// config, unless overridden by `REPROXY_ENVIRONMENT` and
+/// `REPROXY_INSTANCE`. Only the first call has an effect.
+pub fn init(environment: Option<&str>, instance: Option<&str>) {
+    let label = |variable, configured: Option<&str>| {
+        std::env::var(variable)
+            .ok()
+            .or(configured.map(str::to_string))
+            .filter(|value| !value.is_empty())
+    };
+    let _ = LABELS.set(Labels {
+        environment: label(ENVIRONMENT_ENV, environment),
+        instance: label(INSTANCE_ENV, instance),
+    });
+}
+
+/// The labels of the process, empty until [`init`].
+pub fn get() -> &'static Labels {
+    LABELS.get_or_init(Labels::default)
+}
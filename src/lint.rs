@@ -0,0 +1,241 @@
+use argh::FromArgs;
+use std::net::IpAddr;
+
+use crate::{
+    load_config, parse_config, probe, ssrf, Config, HeaderKeyword, ProxyHeaderConfig,
+    ProxyItemConfig,
+};
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "lint")]
+/// check a config file for likely mistakes beyond what is rejected at startup
+pub struct LintCommand {
+    /// the configuration file to check
+    #[argh(option, short = 'c')]
+    config: String,
+}
+
+/// Validates the config like startup does, then prints a warning for each
+/// foot-gun found and fails if there was any.
+pub fn run(command: &LintCommand) -> anyhow::Result<()> {
+    let config = load_config(&command.config)?;
+    let table = parse_config(&config)?;
+    let warnings = lint(&config, &table);
+    for warning in &warnings {
+        println!("warning: {warning}");
+    }
+    if !warnings.is_empty() {
+        anyhow::bail!("{} warning(s) in `{}`", warnings.len(), command.config);
+    }
+    println!("{}: no issues found", command.config);
+    Ok(())
+}
+
+fn lint(config: &Config, table: &crate::ProxyTable) -> Vec<String> {
+    let mut warnings = Vec::new();
+    // table.items is in the order rules are tried
+    let rules: Vec<(&str, &ProxyItemConfig, &regex::Regex)> = table
+        .items
+        .iter()
+        .map(|item| (item.name.as_str(), &config.items[&item.name], &item.regex))
+        .collect();
+
+    for (index, &(name, item, regex)) in rules.iter().enumerate() {
+        let mut warn = |message: String| warnings.push(format!("{name}: {message}"));
+
+        // a leading `.*` is unanchored on purpose
+        let pattern = item.r#match.as_str();
+        if !pattern.starts_with('^') && !pattern.starts_with(".*") && !pattern.starts_with("(.*") {
+            warn(format!(
+                "pattern `{}` is not anchored with `^`, so it also matches inside other hosts and paths",
+                item.r#match
+            ));
+        }
+        if let Some((earlier, _, _)) = rules[..index]
+            .iter()
+            .find(|(_, earlier, earlier_regex)| shadows(earlier, earlier_regex, item, regex))
+        {
+            warn(format!(
+                "never matches, rule `{earlier}` before it takes the same requests"
+            ));
+        }
+        let timeouts = [
+            item.first_byte_timeout,
+            item.read_timeout,
+            item.request_timeout,
+            config.read_timeout,
+            config.request_timeout,
+        ];
+        if timeouts.iter().all(Option::is_none) {
+            warn(
+                "has no `read_timeout` or `request_timeout`, a stalled upstream holds requests open indefinitely"
+                    .to_string(),
+            );
+        }
+        if forwards_authorization(item) {
+            let targets = item
+                .target
+                .iter()
+                .chain(&item.targets)
+                .chain(&item.fallback_target);
+            for target in targets {
+                if let Some(host) = third_party_host(target) {
+                    warn(format!(
+                        "passes the client's Authorization header through to `{host}`"
+                    ));
+                }
+            }
+        }
+        if item.follow_redirect && item.redirect_allowlist.is_empty() {
+            warn("follows redirects to any host, consider a `redirect_allowlist`".to_string());
+        }
+        if item.allow_internal_targets && config.block_internal_targets {
+            warn("is exempt from `block_internal_targets`".to_string());
+        }
+    }
+    warnings
+}
+
+/// Whether `earlier` takes every request `later` would, so `later` is never
+/// used. Only the obvious cases are detected: an unconditional earlier rule
+/// matching everything, or matching the literal url a later rule spells out.
+fn shadows(
+    earlier: &ProxyItemConfig,
+    earlier_regex: &regex::Regex,
+    later: &ProxyItemConfig,
+    later_regex: &regex::Regex,
+) -> bool {
+    let unconditional = earlier.hosts.is_empty()
+        && earlier.cookies.is_empty()
+        && earlier.accept.is_none()
+        && earlier.accept_language.is_none()
+        && earlier.content_type.is_none()
+        && earlier.login.is_none();
+    if !unconditional {
+        return false;
+    }
+    let catch_all = ["", "example.com/", "example.com/some/path?query"]
+        .iter()
+        .all(|url| earlier_regex.is_match(url));
+    let literal = later.r#match.trim_start_matches('^').trim_end_matches('$');
+    // dots in host names are left unescaped more often than not
+    let spelled_out = regex::escape(literal).replace("\\.", ".") == literal
+        && later_regex.is_match(literal)
+        && earlier_regex.is_match(literal);
+    catch_all || spelled_out
+}
+
+fn forwards_authorization(item: &ProxyItemConfig) -> bool {
+    let passthrough = |action: &ProxyHeaderConfig| {
+        matches!(
+            action,
+            ProxyHeaderConfig::Passthrough | ProxyHeaderConfig::Keyword(HeaderKeyword::Passthrough)
+        )
+    };
+    match item
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+    {
+        Some((_, action)) => passthrough(action),
+        None => item.headers.get("$default").is_some_and(passthrough),
+    }
+}
+
+/// The host of `target` unless it is local: a loopback or private address,
+/// `localhost`, or a single label or `.local`/`.internal`/`.lan` name.
+fn third_party_host(target: &str) -> Option<String> {
+    let (host, _) = probe::static_authority(target)?;
+    let local = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => ssrf::is_internal(ip),
+        Err(_) => {
+            !host.contains('.')
+                || [".local", ".internal", ".lan", ".localhost"]
+                    .iter()
+                    .any(|suffix| host.ends_with(suffix))
+        }
+    };
+    (!local).then_some(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warnings(yaml: &str) -> Vec<String> {
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let table = parse_config(&config).unwrap();
+        lint(&config, &table)
+    }
+
+    #[test]
+    fn flags_rules_shadowed_by_a_catch_all() {
+        let warnings = warnings(
+            "
+all:
+  match: \"^(.*)\"
+  target: \"http://127.0.0.1:3400/$1\"
+  read_timeout: 10s
+api:
+  match: \"^api.example.com/(.*)\"
+  target: \"http://127.0.0.1:3401/$1\"
+  read_timeout: 10s
+",
+        );
+        assert_eq!(
+            warnings,
+            ["api: never matches, rule `all` before it takes the same requests"]
+        );
+    }
+
+    #[test]
+    fn flags_rules_shadowed_by_a_literal_url() {
+        let warnings = warnings(
+            "
+read_timeout: 10s
+docs:
+  match: \"^docs.example.com/\"
+  target: \"http://127.0.0.1:3400/\"
+index:
+  match: \"^docs.example.com/$\"
+  target: \"http://127.0.0.1:3401/\"
+",
+        );
+        assert_eq!(
+            warnings,
+            ["index: never matches, rule `docs` before it takes the same requests"]
+        );
+    }
+
+    #[test]
+    fn leaves_conditional_rules_alone() {
+        let warnings = warnings(
+            "
+read_timeout: 10s
+beta:
+  match: \"^(.*)\"
+  cookies:
+    beta: \"1\"
+  target: \"http://127.0.0.1:3400/$1\"
+all:
+  match: \"^(.*)\"
+  target: \"http://127.0.0.1:3401/$1\"
+",
+        );
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn flags_unanchored_patterns_and_missing_timeouts() {
+        let warnings = warnings(
+            "
+loose:
+  match: \"example.com/(.*)\"
+  target: \"http://127.0.0.1:3400/$1\"
+",
+        );
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("is not anchored"));
+        assert!(warnings[1].contains("has no `read_timeout`"));
+    }
+}
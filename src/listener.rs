@@ -0,0 +1,396 @@
+use axum::{
+    body::{Body, BoxBody},
+    extract::ConnectInfo,
+    http::{
+        header::{CONNECTION, RETRY_AFTER},
+        HeaderValue, Request, Response, StatusCode, Version,
+    },
+    Router,
+};
+use hyper::{
+    body::{HttpBody, SizeHint},
+    server::conn::Http,
+    HeaderMap,
+};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{watch, Notify, OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::{access_log, client_ip, errors, AppState};
+
+/// Marks the requests of connections secured with TLS.
+#[derive(Clone, Copy)]
+pub struct Tls;
+
+/// Limits applied to every inbound connection.
+#[derive(Clone)]
+pub struct ConnectionLimits {
+    pub keepalive_timeout: Option<Duration>,
+    pub max_requests: Option<usize>,
+    pub h2_ping_interval: Option<Duration>,
+    pub h2_ping_timeout: Duration,
+    /// shared by all listeners
+    pub in_flight: Option<Arc<InFlightLimit>>,
+}
+
+/// Caps the requests handled at once across all connections, from their
+/// arrival until their response body is sent. Requests over the cap wait up
+/// to `queue_timeout` for a slot, then get a 503.
+pub struct InFlightLimit {
+    slots: Arc<Semaphore>,
+    queue_timeout: Duration,
+    /// whose current rules shed requests are answered and logged with
+    state: Arc<AppState>,
+}
+
+impl InFlightLimit {
+    pub fn new(max: usize, queue_timeout: Duration, state: Arc<AppState>) -> Self {
+        InFlightLimit {
+            slots: Arc::new(Semaphore::new(max)),
+            queue_timeout,
+            state,
+        }
+    }
+
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        tokio::time::timeout(self.queue_timeout, self.slots.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// The answer to a request that found no slot in time, in the error
+    /// format of the rules and logged like the requests they handle.
+    pub fn overloaded(&self, request: &Request<Body>, peer: SocketAddr) -> Response<Body> {
+        tracing::warn!(peer = ?peer, "in-flight request limit reached");
+        let table = self.state.table.read().unwrap().clone();
+        let mut response = errors::respond(
+            table.error_format,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "overloaded",
+            "too many requests are being handled, try again later",
+            &errors::request_id(request.headers()),
+            None,
+        );
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+        let remote = client_ip::Remote {
+            peer,
+            client: client_ip::resolve(peer, request.headers(), &table.trusted_proxies),
+        };
+        let host = client_ip::resolve_host(
+            peer,
+            request.headers(),
+            request.uri(),
+            &table.trusted_proxies,
+        );
+        let url = host.unwrap_or_default()
+            + request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str());
+        access_log::record(request, remote, &url, &Default::default(), &response);
+        response
+    }
+}
+
+/// Time a client gets to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells open connections to wind down when the process stops and keeps
+/// count of them until they're closed.
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    open: AtomicUsize,
+    closed: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            started: watch::channel(false).0,
+            open: AtomicUsize::new(0),
+            closed: Notify::new(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Asks every connection to finish its in-flight requests and close,
+    /// returning how many are open.
+    pub fn begin(&self) -> usize {
+        self.started.send_replace(true);
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Changes to true once connections are asked to wind down.
+    pub fn started(&self) -> watch::Receiver<bool> {
+        self.started.subscribe()
+    }
+
+    /// Waits up to `timeout` for the connections to close, returning how many
+    /// are still open.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        let closed = async {
+            loop {
+                let closed = self.closed.notified();
+                if self.open.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                closed.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, closed).await;
+        self.open.load(Ordering::SeqCst)
+    }
+}
+
+/// Counts a connection as open until dropped.
+pub struct ConnectionGuard(Arc<Shutdown>);
+
+impl ConnectionGuard {
+    pub fn new(shutdown: Arc<Shutdown>) -> Self {
+        shutdown.open.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(shutdown)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+        self.0.closed.notify_waiters();
+    }
+}
+
+/// Accepts connections on `listener`, terminating TLS first when `tls` is
+/// given.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    limits: ConnectionLimits,
+    tls: Option<TlsAcceptor>,
+    shutdown: Arc<Shutdown>,
+) {
+    let mut http = Http::new();
+    http.http1_keep_alive(limits.keepalive_timeout != Some(Duration::ZERO))
+        .http2_keep_alive_interval(limits.h2_ping_interval)
+        .http2_keep_alive_timeout(limits.h2_ping_timeout);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::error!(error = ?err, "accept");
+                continue;
+            }
+        };
+        let http = http.clone();
+        let app = app.clone();
+        let limits = limits.clone();
+        let shutdown = shutdown.clone();
+        match &tls {
+            Some(tls) => {
+                let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream));
+                tokio::spawn(async move {
+                    match handshake.await {
+                        Ok(Ok(stream)) => {
+                            drive(http, stream, peer, true, app, limits, shutdown).await
+                        }
+                        Ok(Err(err)) => {
+                            tracing::debug!(error = ?err, peer = ?peer, "tls handshake")
+                        }
+                        Err(_) => tracing::debug!(peer = ?peer, "tls handshake timed out"),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(drive(http, stream, peer, false, app, limits, shutdown));
+            }
+        }
+    }
+}
+
+/// Serves the requests of one connection until it closes or is retired.
+async fn drive<S>(
+    http: Http,
+    stream: S,
+    peer: SocketAddr,
+    tls: bool,
+    app: Router,
+    limits: ConnectionLimits,
+    shutdown: Arc<Shutdown>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut stopping = shutdown.started();
+    let _open = ConnectionGuard::new(shutdown);
+    let activity = Arc::new(Activity::default());
+    let service = {
+        let activity = activity.clone();
+        let max_requests = limits.max_requests;
+        let in_flight = limits.in_flight.clone();
+        hyper::service::service_fn(move |mut request: Request<Body>| {
+            request.extensions_mut().insert(ConnectInfo(peer));
+            if tls {
+                request.extensions_mut().insert(Tls);
+            }
+            let (guard, served) = activity.begin();
+            let last = max_requests.is_some_and(|max| served >= max)
+                && request.version() < Version::HTTP_2;
+            let app = app.clone();
+            let in_flight = in_flight.clone();
+            async move {
+                // held by the response body until it is sent
+                let slot = match &in_flight {
+                    Some(limit) => match limit.acquire().await {
+                        Some(slot) => Some(slot),
+                        None => {
+                            drop(guard);
+                            return Ok(limit.overloaded(&request, peer).map(axum::body::boxed));
+                        }
+                    },
+                    None => None,
+                };
+                let mut response = app.oneshot(request).await;
+                if let Ok(response) = &mut response {
+                    if last {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
+                }
+                drop(guard);
+                response.map(|response| {
+                    response.map(|body| match slot {
+                        Some(slot) => BoxBody::new(Holding { body, _slot: slot }),
+                        None => body,
+                    })
+                })
+            }
+        })
+    };
+    let connection = http.serve_connection(stream, service).with_upgrades();
+    tokio::pin!(connection);
+    let mut retiring = false;
+    loop {
+        tokio::select! {
+            result = connection.as_mut() => {
+                if let Err(err) = result {
+                    tracing::debug!(error = ?err, peer = ?peer, "connection");
+                }
+                break;
+            }
+            _ = activity.exhausted(&limits), if !retiring => {
+                retiring = true;
+                connection.as_mut().graceful_shutdown();
+            }
+            _ = stopping.wait_for(|started| *started), if !retiring => {
+                retiring = true;
+                connection.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// A response body keeping its request's in-flight slot until dropped.
+struct Holding {
+    body: BoxBody,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl HttpBody for Holding {
+    type Data = <BoxBody as HttpBody>::Data;
+    type Error = <BoxBody as HttpBody>::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[derive(Default)]
+struct Activity {
+    in_flight: AtomicUsize,
+    served: AtomicUsize,
+    changed: Notify,
+}
+
+struct RequestGuard(Arc<Activity>);
+
+impl Activity {
+    /// Records the start of a request, returning how many requests the
+    /// connection has served including this one.
+    fn begin(self: &Arc<Self>) -> (RequestGuard, usize) {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let served = self.served.fetch_add(1, Ordering::SeqCst) + 1;
+        self.changed.notify_one();
+        (RequestGuard(self.clone()), served)
+    }
+
+    /// Resolves once the connection is idle and has either served its maximum
+    /// number of requests or stayed idle for longer than the keep-alive timeout.
+    async fn exhausted(&self, limits: &ConnectionLimits) {
+        loop {
+            let changed = self.changed.notified();
+            if self.in_flight.load(Ordering::SeqCst) > 0 {
+                changed.await;
+                continue;
+            }
+            if limits
+                .max_requests
+                .is_some_and(|max| self.served.load(Ordering::SeqCst) >= max)
+            {
+                return;
+            }
+            match limits.keepalive_timeout {
+                Some(timeout) => {
+                    if tokio::time::timeout(timeout, changed).await.is_err() {
+                        return;
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.changed.notify_one();
+    }
+}
@@ -0,0 +1,188 @@
+use std::{path::Path, str::FromStr, sync::Arc};
+use tracing::{Event, Level, Subscriber};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_subscriber::{
+    filter::Targets,
+    fmt::{
+        self,
+        format::{FormatEvent, FormatFields, Writer},
+        FmtContext,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::{access_log, labels, report};
+
+/// Target of the per-request access log events.
+pub const ACCESS: &str = "access";
+
+#[derive(Clone, Copy, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format `{s}`, expected `text` or `json`"
+            )),
+        }
+    }
+}
+
+pub struct LogOptions {
+    /// `-` for stdout, `off`, or a file path
+    pub access_log: String,
+    pub access_log_format: access_log::AccessFormat,
+    /// `-` for stderr, or a file path
+    pub error_log: String,
+    /// an env-filter directive, falling back to `RUST_LOG`
+    pub error_log_level: Option<String>,
+    pub error_log_format: LogFormat,
+}
+
+/// Installs the access and error log sinks, and counts requests into `stats`.
+/// The returned guards flush the background writers when dropped and must be
+/// kept alive until exit.
+pub fn init(options: &LogOptions, stats: Arc<report::Stats>) -> anyhow::Result<Vec<WorkerGuard>> {
+    let mut guards = Vec::new();
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> =
+        vec![report::Counter(stats).boxed()];
+
+    if options.access_log != "off" {
+        let (writer, guard) = writer(&options.access_log, false)?;
+        guards.push(guard);
+        layers.push(
+            access_log::AccessLayer::new(writer, options.access_log_format.clone())
+                .with_filter(Targets::new().with_target(ACCESS, Level::TRACE))
+                .boxed(),
+        );
+    }
+
+    let filter = match &options.error_log_level {
+        Some(level) => EnvFilter::try_new(level)?,
+        None => EnvFilter::from_default_env(),
+    }
+    .add_directive(format!("{ACCESS}=off").parse()?);
+    let (writer, guard) = writer(&options.error_log, true)?;
+    guards.push(guard);
+    layers.push(
+        layer(writer, options.error_log_format, options.error_log == "-")
+            .with_filter(filter)
+            .boxed(),
+    );
+
+    tracing_subscriber::registry().with(layers).try_init()?;
+    log_panics();
+    Ok(guards)
+}
+
+/// Sends panic messages to the error log, with a backtrace, instead of
+/// printing them to stderr.
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        tracing::error!(panic = %info, backtrace = %backtrace, "panic");
+    }));
+}
+
+fn layer(
+    writer: NonBlocking,
+    format: LogFormat,
+    ansi: bool,
+) -> Box<dyn Layer<Registry> + Send + Sync> {
+    let labels = labels::get();
+    match format {
+        LogFormat::Text if labels.is_empty() => {
+            fmt::layer().with_ansi(ansi).with_writer(writer).boxed()
+        }
+        LogFormat::Text => fmt::layer()
+            .with_ansi(ansi)
+            .event_format(Labelled {
+                inner: fmt::format().with_ansi(ansi),
+                labels,
+                format,
+            })
+            .with_writer(writer)
+            .boxed(),
+        LogFormat::Json if labels.is_empty() => fmt::layer().json().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .event_format(Labelled {
+                inner: fmt::format().json(),
+                labels,
+                format,
+            })
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Adds the [`labels`] to the lines of the `inner` format: as trailing
+/// `key="value"` pairs in text, as leading keys in JSON.
+struct Labelled<F> {
+    inner: F,
+    labels: &'static labels::Labels,
+    format: LogFormat,
+}
+
+impl<S, N, F> FormatEvent<S, N> for Labelled<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let mut line = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut line), event)?;
+        let line = line.trim_end_matches('\n');
+        match self.format {
+            LogFormat::Text => {
+                writer.write_str(line)?;
+                for (name, value) in self.labels.iter() {
+                    write!(writer, " {name}={value:?}")?;
+                }
+            }
+            LogFormat::Json => {
+                let mut labels = serde_json::to_string(self.labels).map_err(|_| std::fmt::Error)?;
+                // splice the labels into the event's object: `{labels,event}`
+                labels.pop();
+                match line.strip_prefix('{') {
+                    Some(event) if event != "}" => write!(writer, "{labels},{event}")?,
+                    _ => write!(writer, "{labels}}}")?,
+                }
+            }
+        }
+        writeln!(writer)
+    }
+}
+
+fn writer(destination: &str, stderr: bool) -> anyhow::Result<(NonBlocking, WorkerGuard)> {
+    Ok(match destination {
+        "-" if stderr => tracing_appender::non_blocking(std::io::stderr()),
+        "-" => tracing_appender::non_blocking(std::io::stdout()),
+        path => {
+            let path = Path::new(path);
+            let file_name = path
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("invalid log file `{}`", path.display()))?;
+            let directory = path.parent().unwrap_or_else(|| Path::new("."));
+            tracing_appender::non_blocking(tracing_appender::rolling::never(directory, file_name))
+        }
+    })
+}
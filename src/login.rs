@@ -0,0 +1,418 @@
+use axum::{
+    body::{Body, HttpBody},
+    http::{header, HeaderValue, Method, Request, Response, StatusCode},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::listener;
+
+const COOKIE: &str = "reproxy_session";
+/// Login forms are tiny, anything larger is not one.
+const MAX_FORM_SIZE: usize = 8 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub struct LoginConfig {
+    /// user names with their plain text passwords
+    #[serde(default)]
+    users: HashMap<String, String>,
+    /// htpasswd file with bcrypt hashes, as written by `htpasswd -B`
+    #[serde(default)]
+    htpasswd: Option<String>,
+    #[serde(default = "default_session_ttl", with = "humantime_serde")]
+    session_ttl: Duration,
+    /// shown as the heading of the login page
+    #[serde(default)]
+    title: Option<String>,
+}
+
+fn default_session_ttl() -> Duration {
+    Duration::from_secs(12 * 60 * 60)
+}
+
+enum Credential {
+    Plain(String),
+    Bcrypt(String),
+}
+
+/// A login page guarding a rule, remembering signed in clients by cookie.
+pub struct Login {
+    credentials: HashMap<String, Credential>,
+    session_ttl: Duration,
+    title: String,
+}
+
+struct Session {
+    rule: String,
+    expires: Instant,
+}
+
+/// Sessions outlive config reloads, so they are kept for the whole process.
+fn sessions() -> &'static Mutex<HashMap<String, Session>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<String, Session>>> = OnceLock::new();
+    SESSIONS.get_or_init(Default::default)
+}
+
+impl Login {
+    pub fn new(config: &LoginConfig) -> anyhow::Result<Self> {
+        let mut credentials: HashMap<_, _> = config
+            .users
+            .iter()
+            .map(|(user, password)| (user.clone(), Credential::Plain(password.clone())))
+            .collect();
+        if let Some(path) = &config.htpasswd {
+            let content = std::fs::read_to_string(path)
+                .map_err(|err| anyhow::anyhow!("can't read htpasswd file `{path}`: {err}"))?;
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                let Some((user, hash)) = line.trim().split_once(':') else {
+                    anyhow::bail!("{path}: malformed line `{line}`");
+                };
+                if !hash.starts_with("$2") {
+                    anyhow::bail!("{path}: `{user}` is not bcrypt hashed, use `htpasswd -B`");
+                }
+                credentials.insert(user.to_string(), Credential::Bcrypt(hash.to_string()));
+            }
+        }
+        if credentials.is_empty() {
+            anyhow::bail!("login needs `users` or an `htpasswd` file");
+        }
+        Ok(Login {
+            credentials,
+            session_ttl: config.session_ttl,
+            title: config
+                .title
+                .clone()
+                .unwrap_or_else(|| "Sign in".to_string()),
+        })
+    }
+
+    /// Answers `request` with the login page or a login attempt unless it
+    /// carries a live session cookie for `rule`, in which case it's let
+    /// through with `None`.
+    pub async fn check(
+        &self,
+        request: &mut Request<Body>,
+        rule: &str,
+    ) -> anyhow::Result<Option<Response<Body>>> {
+        if let Some(token) = session_cookie(request) {
+            let now = Instant::now();
+            let mut sessions = sessions().lock().unwrap();
+            sessions.retain(|_, session| session.expires > now);
+            if sessions
+                .get(token)
+                .is_some_and(|session| session.rule == rule)
+            {
+                drop(sessions);
+                // the session is the proxy's, not the upstream's business
+                strip_session_cookie(request);
+                return Ok(None);
+            }
+        }
+
+        let is_form = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+        if request.method() != Method::POST || !is_form {
+            return Ok(Some(self.page(StatusCode::UNAUTHORIZED, None)));
+        }
+        let Some(form) = read_form(request.body_mut()).await? else {
+            return Ok(Some(self.page(StatusCode::PAYLOAD_TOO_LARGE, None)));
+        };
+        let field = |name: &str| {
+            url::form_urlencoded::parse(&form)
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        if field("reproxy_login").is_none() {
+            return Ok(Some(self.page(StatusCode::UNAUTHORIZED, None)));
+        }
+        let user = field("username").unwrap_or_default();
+        let password = field("password").unwrap_or_default();
+        if !self.verify(&user, &password).await {
+            tracing::warn!(matched = rule, user, "failed login");
+            let error = "Invalid user name or password.";
+            return Ok(Some(self.page(StatusCode::UNAUTHORIZED, Some(error))));
+        }
+
+        let token = new_token()?;
+        let now = Instant::now();
+        let mut sessions = sessions().lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            token.clone(),
+            Session {
+                rule: rule.to_string(),
+                expires: now + self.session_ttl,
+            },
+        );
+        drop(sessions);
+        let secure = if request.extensions().get::<listener::Tls>().is_some() {
+            "; Secure"
+        } else {
+            ""
+        };
+        // send the browser back to the page it asked for, now as a GET
+        let location = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .to_string();
+        Ok(Some(
+            Response::builder()
+                .status(StatusCode::SEE_OTHER)
+                .header(header::LOCATION, location)
+                .header(
+                    header::SET_COOKIE,
+                    format!(
+                        "{COOKIE}={token}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{secure}",
+                        self.session_ttl.as_secs()
+                    ),
+                )
+                .body(Body::empty())?,
+        ))
+    }
+
+    async fn verify(&self, user: &str, password: &str) -> bool {
+        match self.credentials.get(user) {
+            Some(Credential::Plain(expected)) => constant_time_eq(expected, password),
+            Some(Credential::Bcrypt(hash)) => {
+                // bcrypt is slow on purpose, too slow for a runtime worker
+                let (password, hash) = (password.to_string(), hash.clone());
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+                    .await
+                    .is_ok_and(|verified| verified.unwrap_or(false))
+            }
+            None => false,
+        }
+    }
+
+    fn page(&self, status: StatusCode, error: Option<&str>) -> Response<Body> {
+        let title = escape(&self.title);
+        let error = error.map_or(String::new(), |error| {
+            format!("<p class=\"error\">{}</p>", escape(error))
+        });
+        let html = format!(
+            r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; background: #f4f4f5; display: flex; justify-content: center; padding-top: 15vh; margin: 0; }}
+form {{ background: #fff; padding: 2em; border-radius: 8px; box-shadow: 0 1px 4px #0002; width: 18em; }}
+input {{ display: block; width: 100%; box-sizing: border-box; margin: .4em 0 1em; padding: .5em; }}
+.error {{ color: #b91c1c; }}
+</style>
+</head>
+<body>
+<form method="post">
+<h1>{title}</h1>
+{error}
+<input type="hidden" name="reproxy_login" value="1">
+<label>User name<input name="username" autocomplete="username" autofocus></label>
+<label>Password<input name="password" type="password" autocomplete="current-password"></label>
+<button type="submit">Sign in</button>
+</form>
+</body>
+</html>
+"#
+        );
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::from(html))
+            .unwrap()
+    }
+}
+
+fn session_cookie(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// Removes the session cookie from the `Cookie` headers of `request`, and
+/// the headers left empty.
+fn strip_session_cookie(request: &mut Request<Body>) {
+    let values: Vec<String> = request
+        .headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .map(str::trim)
+                .filter(|pair| pair.split_once('=').map_or(*pair, |(key, _)| key) != COOKIE)
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+        .filter(|value| !value.is_empty())
+        .collect();
+    request.headers_mut().remove(header::COOKIE);
+    for value in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            request.headers_mut().append(header::COOKIE, value);
+        }
+    }
+}
+
+/// Reads a form body, or `None` if it exceeds [`MAX_FORM_SIZE`].
+async fn read_form(body: &mut Body) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut form = Vec::new();
+    while let Some(chunk) = body.data().await {
+        form.extend_from_slice(&chunk?);
+        if form.len() > MAX_FORM_SIZE {
+            return Ok(None);
+        }
+    }
+    Ok(Some(form))
+}
+
+fn new_token() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login() -> Login {
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        Login {
+            credentials: HashMap::from([
+                (
+                    "alice".to_string(),
+                    Credential::Plain("hunter2".to_string()),
+                ),
+                ("bob".to_string(), Credential::Bcrypt(hash)),
+            ]),
+            session_ttl: Duration::from_secs(60),
+            title: "Sign in".to_string(),
+        }
+    }
+
+    fn sign_in(user: &str, password: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/app?page=2")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!(
+                "reproxy_login=1&username={user}&password={password}"
+            )))
+            .unwrap()
+    }
+
+    fn session_of(response: &Response<Body>) -> String {
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let (pair, _) = cookie.split_once(';').unwrap();
+        pair.to_string()
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_login_without_a_session() {
+        let mut request = Request::new(Body::empty());
+        let response = login().check(&mut request, "rule").await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_passwords() {
+        let login = login();
+        for (user, password) in [("alice", "hunter3"), ("bob", "hunter2"), ("eve", "")] {
+            let mut request = sign_in(user, password);
+            let response = login.check(&mut request, "rule").await.unwrap().unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(!response.headers().contains_key(header::SET_COOKIE));
+        }
+    }
+
+    #[tokio::test]
+    async fn lets_sessions_through_without_their_cookie() {
+        let login = login();
+        let mut request = sign_in("bob", "s3cret");
+        let response = login.check(&mut request, "rule").await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[header::LOCATION], "/app?page=2");
+        let session = session_of(&response);
+
+        let mut request = Request::builder()
+            .header(header::COOKIE, format!("theme=dark; {session}"))
+            .body(Body::empty())
+            .unwrap();
+        assert!(login.check(&mut request, "rule").await.unwrap().is_none());
+        assert_eq!(request.headers()[header::COOKIE], "theme=dark");
+
+        // sessions are bound to the rule they were opened for
+        let mut request = Request::builder()
+            .header(header::COOKIE, session)
+            .body(Body::empty())
+            .unwrap();
+        assert!(login.check(&mut request, "other").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn marks_cookies_secure_over_tls() {
+        let login = login();
+        let mut request = sign_in("alice", "hunter2");
+        let response = login.check(&mut request, "rule").await.unwrap().unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(!cookie.contains("Secure"));
+
+        let mut request = sign_in("alice", "hunter2");
+        request.extensions_mut().insert(listener::Tls);
+        let response = login.check(&mut request, "rule").await.unwrap().unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(cookie.ends_with("; Secure"));
+    }
+
+    #[test]
+    fn strips_only_the_session_cookie() {
+        let mut request = Request::builder()
+            .header(header::COOKIE, format!("{COOKIE}=abc"))
+            .header(header::COOKIE, format!("a=1; {COOKIE}=abc; b=2"))
+            .body(Body::empty())
+            .unwrap();
+        strip_session_cookie(&mut request);
+        let cookies: Vec<_> = request.headers().get_all(header::COOKIE).iter().collect();
+        assert_eq!(cookies, ["a=1; b=2"]);
+    }
+
+    #[test]
+    fn compares_passwords_in_full() {
+        assert!(constant_time_eq("hunter2", "hunter2"));
+        assert!(!constant_time_eq("hunter2", "hunter"));
+        assert!(!constant_time_eq("hunter2", "hunter3"));
+    }
+}
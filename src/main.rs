@@ -1,253 +1,2213 @@
 use axum::{
-    body::Body,
-    extract::{Host, State},
-    http::Request,
+    body::{Body, HttpBody},
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode, Version},
     response::Response,
     routing::any,
     Router,
 };
+use futures_util::FutureExt;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use argh::FromArgs;
 
+mod access_log;
+mod admin;
+mod alert;
+mod attempt;
+mod authority;
+mod balance;
+mod breaker;
+mod buffer;
+mod cache;
+mod canonical;
+mod client_ip;
+mod conditions;
+mod connector;
+mod content_type;
+mod cookie;
+mod daemon;
+mod downgrade;
+mod drain;
+mod errors;
+mod header_order;
+mod health;
+mod heartbeat;
+mod host_guard;
+mod inline;
+mod labels;
+mod lint;
+mod listener;
+mod logging;
+mod login;
+mod memory;
+mod overrides;
+mod pin;
+mod probe;
+mod quic;
+mod ratelimit;
+mod recycle;
+mod redact;
+mod redirect;
+mod reload;
+mod repl;
+mod report;
+mod scheme;
+mod selftest;
+mod spki;
+mod ssrf;
+mod supervisor;
+mod timeout;
+mod timing;
+mod tls;
+mod unix;
+mod upstream;
+mod websocket;
+
+use balance::{BalanceStrategy, Balancer};
+use drain::{Draining, Target};
+use errors::ErrorFormat;
+
 #[derive(FromArgs)]
 /// reproxy - REgex (reserve) PROXY
 struct CliArgs {
-    /// specifies the host IP address
-    #[argh(option, short = 'h', default = "String::from(\"127.0.0.1\")")]
-    host: String,
+    /// host IP address to listen on, repeatable (default: 127.0.0.1)
+    #[argh(option, short = 'h')]
+    host: Vec<String>,
+
+    /// port to listen on, repeatable; ports pair up with hosts in order, or
+    /// with every host when only one is given; 0 picks a free port, printed
+    /// once bound (default: 3333)
+    #[argh(option, short = 'p')]
+    port: Vec<u16>,
+
+    /// idle time after which keep-alive connections are closed, 0 disables
+    /// keep-alive (default: 75s)
+    #[argh(option)]
+    keepalive_timeout: Option<humantime::Duration>,
+
+    /// maximum number of requests served over one connection
+    #[argh(option)]
+    max_requests_per_connection: Option<usize>,
+
+    /// interval of HTTP/2 keep-alive pings sent to idle clients
+    #[argh(option)]
+    h2_ping_interval: Option<humantime::Duration>,
+
+    /// time to wait for a HTTP/2 keep-alive ping to be acknowledged
+    /// (default: 20s)
+    #[argh(option)]
+    h2_ping_timeout: Option<humantime::Duration>,
+
+    /// maximum number of requests handled at once across all connections
+    #[argh(option)]
+    max_in_flight_requests: Option<usize>,
+
+    /// time a request over `--max-in-flight-requests` waits for a slot
+    /// before it is answered with 503 (default: 0s)
+    #[argh(option)]
+    in_flight_queue_timeout: Option<humantime::Duration>,
+
+    /// address of the admin API serving the loaded rules, their counters
+    /// and the config as JSON, such as `127.0.0.1:9000`; it has no
+    /// authentication, so keep it private
+    #[argh(option)]
+    admin_listen: Option<SocketAddr>,
+
+    /// time open connections get to finish on SIGTERM or Ctrl-C before they
+    /// are aborted (default: 30s)
+    #[argh(option)]
+    shutdown_timeout: Option<humantime::Duration>,
 
-    /// specifies the port number
-    #[argh(option, short = 'p', default = "3333")]
-    port: u16,
+    /// URL the shutdown report is posted to as JSON
+    #[argh(option)]
+    shutdown_webhook: Option<String>,
+
+    /// access log destination: `-` for stdout, `off`, or a file path
+    /// (default: -)
+    #[argh(option, default = "String::from(\"-\")")]
+    access_log: String,
+
+    /// access log format: text, json, common, combined, or a template such as
+    /// `$client $method $path $status $rule $upstream_time` (default: text)
+    #[argh(option, default = "Default::default()")]
+    access_log_format: access_log::AccessFormat,
+
+    /// error log destination: `-` for stderr or a file path (default: -)
+    #[argh(option, default = "String::from(\"-\")")]
+    error_log: String,
+
+    /// error log filter such as `info` or `reproxy=debug` (default: RUST_LOG)
+    #[argh(option)]
+    error_log_level: Option<String>,
+
+    /// error log format: text or json (default: text)
+    #[argh(option, default = "Default::default()")]
+    error_log_format: logging::LogFormat,
 
     /// specifies the configuration file
     #[argh(option, short = 'c')]
     config: Option<String>,
 
+    /// detach from the terminal and run in the background (unix only)
+    #[argh(switch)]
+    daemon: bool,
+
+    /// run this many worker processes sharing the port through SO_REUSEPORT,
+    /// restarting any that exit (unix only)
+    #[argh(option)]
+    workers: Option<usize>,
+
+    /// write the process id to this file
+    #[argh(option)]
+    pid_file: Option<String>,
+
+    /// write the URLs listened on to this file, one per line, once bound
+    #[argh(option)]
+    port_file: Option<String>,
+
+    /// switch to this user after binding the listener (unix only)
+    #[argh(option)]
+    user: Option<String>,
+
+    /// switch to this group after binding the listener (unix only)
+    #[argh(option)]
+    group: Option<String>,
+
+    /// connect to every target at startup and `warn` or `fail` when one
+    /// can't be reached
+    #[argh(option)]
+    probe_targets: Option<probe::ProbeMode>,
+
+    /// PEM certificate chain to serve HTTPS with, overriding `tls_cert`
+    /// from the config file
+    #[argh(option)]
+    tls_cert: Option<String>,
+
+    /// PEM private key of the certificate, overriding `tls_key` from the
+    /// config file
+    #[argh(option)]
+    tls_key: Option<String>,
+
+    /// serve HTTPS on this port and keep plain HTTP on `--port`; without it
+    /// `--port` serves HTTPS only
+    #[argh(option)]
+    tls_port: Option<u16>,
+
+    /// serve HTTP/3 over QUIC on this UDP port with the certificate of the
+    /// HTTPS listener, advertised to other clients with Alt-Svc
+    #[argh(option)]
+    h3_port: Option<u16>,
+
+    /// route synthetic requests to an embedded upstream and report the
+    /// latency of matching, rewriting and forwarding, then exit
+    #[argh(switch)]
+    selftest: bool,
+
+    /// number of requests sent by `--selftest` (default: 10000)
+    #[argh(option, default = "10000")]
+    selftest_requests: usize,
+
+    /// requests `--selftest` keeps in flight at once (default: 16)
+    #[argh(option, default = "16")]
+    selftest_concurrency: usize,
+
     /// show current version
     #[argh(switch)]
     version: bool,
 
-    /// specifies the proxy items directly without config file (unimplemented)
-    #[argh(positional, greedy)]
-    proxy: Option<String>,
+    /// proxy rules used instead of a config file, each as
+    /// `REGEX=>TARGET [follow] [+HEADER...]`
+    #[argh(positional)]
+    proxy: Vec<String>,
+
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Lint(lint::LintCommand),
+    Repl(repl::ReplCommand),
 }
 
 #[derive(Serialize, Deserialize)]
-struct Config(HashMap<String, ProxyItemConfig>);
+struct Config {
+    #[serde(default)]
+    error_format: ErrorFormat,
+    #[serde(default)]
+    block_internal_targets: bool,
+    #[serde(default)]
+    trusted_proxies: client_ip::TrustedProxies,
+    #[serde(default = "default_user_agent")]
+    user_agent: String,
+    #[serde(default)]
+    proxied_by: Option<String>,
+    /// upstream timeouts of rules that don't set their own
+    #[serde(default, with = "humantime_serde")]
+    connect_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    read_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    /// hosts requests may be addressed to, exact or as `*.domain`
+    /// wildcards; others are refused with 421 before any rule is matched
+    #[serde(default)]
+    allowed_hosts: Vec<String>,
+    /// refuses requests whose absolute-form uri disagrees with Host
+    #[serde(default)]
+    reject_authority_mismatch: bool,
+    /// soft limit on the response bodies held by `buffer_response` at once;
+    /// beyond it responses are streamed instead
+    #[serde(default)]
+    max_buffered_memory: Option<bytesize::ByteSize>,
+    /// soft limit on the responses held by the caches of all rules; beyond
+    /// it the least recently used are evicted
+    #[serde(default)]
+    max_cache_memory: Option<bytesize::ByteSize>,
+    /// responses of unknown length to HTTP/1.0 clients are buffered up to
+    /// this size to send a Content-Length; larger ones end with the
+    /// connection
+    #[serde(default = "default_http10_buffer")]
+    http10_buffer: bytesize::ByteSize,
+    #[serde(default)]
+    match_cache_size: usize,
+    #[serde(default)]
+    regex_size_limit: Option<bytesize::ByteSize>,
+    #[serde(default)]
+    regex_dfa_size_limit: Option<bytesize::ByteSize>,
+    /// labels added to every log line, report and alert, unless set by
+    /// `REPROXY_ENVIRONMENT` and `REPROXY_INSTANCE`; read once at startup
+    #[serde(default)]
+    environment: Option<String>,
+    #[serde(default)]
+    instance: Option<String>,
+    /// addresses to listen on as `host:port`, unless `--host` or `--port`
+    /// is given; read once at startup
+    #[serde(default)]
+    listen: Vec<String>,
+    /// certificate and key files of the HTTPS listener, read once at startup
+    #[serde(default)]
+    tls_cert: Option<String>,
+    #[serde(default)]
+    tls_key: Option<String>,
+    /// documents answered before any rule is consulted, by host (`*` for
+    /// all hosts) and path, e.g. `/robots.txt`
+    #[serde(default)]
+    overrides: HashMap<String, HashMap<String, String>>,
+    /// apex domains with the form, `www` or `apex`, the other one is
+    /// redirected to
+    #[serde(default)]
+    canonical_hosts: HashMap<String, canonical::CanonicalForm>,
+    /// rules in the order they appear in the file
+    #[serde(flatten, deserialize_with = "unique_rules")]
+    items: indexmap::IndexMap<String, ProxyItemConfig>,
+}
+
+/// Collects the rules of the config, rejecting a name used twice instead of
+/// letting the later rule silently replace the earlier one.
+fn unique_rules<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<indexmap::IndexMap<String, ProxyItemConfig>, D::Error> {
+    struct Rules;
+
+    impl<'de> serde::de::Visitor<'de> for Rules {
+        type Value = indexmap::IndexMap<String, ProxyItemConfig>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("rules by name")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(
+            self,
+            mut map: A,
+        ) -> Result<Self::Value, A::Error> {
+            let mut rules = indexmap::IndexMap::new();
+            while let Some(name) = map.next_key::<String>()? {
+                if rules.contains_key(&name) {
+                    return Err(serde::de::Error::custom(format!(
+                        "rule `{name}` is defined more than once"
+                    )));
+                }
+                let rule = map.next_value()?;
+                rules.insert(name, rule);
+            }
+            Ok(rules)
+        }
+    }
+
+    deserializer.deserialize_map(Rules)
+}
 
 #[derive(Serialize, Deserialize)]
 struct ProxyItemConfig {
     r#match: String,
-    target: String,
+    /// what the rule is for, shown by the repl
+    #[serde(default)]
+    description: Option<String>,
+    /// team or person responsible for the rule, logged with its requests
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// rules are tried by descending priority, then in config order
+    #[serde(default)]
+    priority: i32,
+    /// host names the rule is limited to, exact or as `*.domain` wildcards
+    #[serde(default)]
+    hosts: Vec<String>,
+    /// networks or addresses of the clients the rule is limited to
+    #[serde(default)]
+    clients: Vec<String>,
+    #[serde(default)]
+    target: Option<String>,
+    /// replicas requests are spread across instead of a single `target`
+    #[serde(default)]
+    targets: Vec<String>,
+    /// tried once when the target fails to respond or answers with one of
+    /// the `fallback_on` statuses; requests with a body are not replayed
+    #[serde(default)]
+    fallback_target: Option<String>,
+    #[serde(default)]
+    fallback_on: Vec<u16>,
+    /// how requests are spread across the `targets`; `ip_hash` keeps each
+    /// client, as resolved behind trusted proxies, on the same one
+    #[serde(default)]
+    balance: BalanceStrategy,
+    #[serde(default = "default_drain_period", with = "humantime_serde")]
+    drain_period: Duration,
     #[serde(default)]
     follow_redirect: bool,
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+    #[serde(default)]
+    redirect_allowlist: Vec<String>,
+    /// whether cross-origin redirects are followed without `Authorization`
+    /// and `Cookie` headers or handed to the client
+    #[serde(default)]
+    redirect_credentials: redirect::RedirectCredentials,
+    #[serde(default)]
+    preserve_host: bool,
+    /// sends the client's address upstream in X-Forwarded-For, after the
+    /// hops a trusted proxy reported
+    #[serde(default)]
+    x_forwarded_for: bool,
+    #[serde(default)]
+    allow_internal_targets: bool,
+    #[serde(default)]
+    force_https: Option<scheme::ForceHttps>,
+    #[serde(default)]
+    pinned_hosts: HashMap<String, pin::PinnedHostConfig>,
+    /// source address for upstream connections on multi-homed hosts
+    #[serde(default)]
+    local_address: Option<IpAddr>,
+    /// time an idle upstream connection is kept for reuse (default: 90s)
+    #[serde(default, with = "humantime_serde")]
+    pool_idle_timeout: Option<Duration>,
+    /// protocol spoken to the targets
+    #[serde(default)]
+    http_version: HttpVersion,
+    /// age and number of requests after which the upstream connection pool
+    /// is replaced, for upstreams that drop long-lived connections
+    #[serde(default, with = "humantime_serde")]
+    max_connection_age: Option<Duration>,
+    #[serde(default)]
+    max_connection_requests: Option<usize>,
+    /// PEM file of CA certificates trusted for upstreams besides the public
+    /// roots, for upstreams with internal certificates
+    #[serde(default)]
+    ca_bundle: Option<String>,
+    /// accepts any upstream certificate, only meant for testing
+    #[serde(default)]
+    insecure_skip_verify: bool,
+    /// name sent as SNI and checked against the upstream certificate in
+    /// place of the target's host, which is still connected to
+    #[serde(default)]
+    tls_server_name: Option<String>,
+    /// upstream public keys to trust, as `sha256/<base64 SPKI hash>`
+    #[serde(default)]
+    tls_pins: Vec<String>,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    proxied_by: Option<String>,
+    /// time to establish the upstream connection, including TLS
+    #[serde(default, with = "humantime_serde")]
+    connect_timeout: Option<Duration>,
+    /// longest wait for the response head or the next part of the body
+    #[serde(default, with = "humantime_serde")]
+    read_timeout: Option<Duration>,
+    /// time the whole upstream exchange may take, body included
+    #[serde(default, with = "humantime_serde")]
+    request_timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    first_byte_timeout: Option<Duration>,
+    #[serde(default)]
+    max_response_header_size: Option<bytesize::ByteSize>,
+    #[serde(default)]
+    buffer_response: Option<bytesize::ByteSize>,
+    /// upstream responses larger than this are answered with 502, or cut
+    /// off once streaming has begun
+    #[serde(default)]
+    max_response_size: Option<bytesize::ByteSize>,
+    /// idle time after which an SSE comment is sent down event streams
+    #[serde(default, with = "humantime_serde")]
+    sse_heartbeat: Option<Duration>,
+    #[serde(default)]
+    error_format: Option<ErrorFormat>,
+    /// access log format of the requests this rule matches, overriding
+    /// `--access-log-format`
+    #[serde(default)]
+    access_log_format: Option<String>,
     #[serde(default)]
     headers: HashMap<String, ProxyHeaderConfig>,
+    /// actions for upstream response headers, which are passed through
+    /// unless `$default` says otherwise
+    #[serde(default)]
+    response_headers: HashMap<String, ProxyHeaderConfig>,
+    /// overrides or sanitizes the Content-Type of upstream responses
+    #[serde(default)]
+    response_content_type: Option<content_type::ResponseContentTypeConfig>,
+    /// replacements of the `Domain` attribute of upstream cookies, by the
+    /// domain set upstream; an empty replacement makes them host-only
+    #[serde(default)]
+    cookie_domain: HashMap<String, String>,
+    /// replacements of the `Path` attribute of upstream cookies, by the path
+    /// prefix set upstream
+    #[serde(default)]
+    cookie_path: HashMap<String, String>,
+    /// cookies the request must carry, with a pattern for the value or null
+    /// to only require presence
+    #[serde(default)]
+    cookies: HashMap<String, Option<String>>,
+    /// pattern one of the Accept media ranges must match, as lowercased
+    /// entries without parameters; those refused with `q=0` don't count
+    #[serde(default)]
+    accept: Option<String>,
+    /// pattern one of the Accept-Language tags must match, likewise
+    #[serde(default)]
+    accept_language: Option<String>,
+    /// pattern the request body's media type must match
+    #[serde(default)]
+    content_type: Option<String>,
+    /// requires clients to sign in on a login page first
+    #[serde(default)]
+    login: Option<login::LoginConfig>,
+    /// checks targets in the background and skips those that are down;
+    /// targets built from capture groups aren't checked
+    #[serde(default)]
+    health_check: Option<health::HealthCheckConfig>,
+    /// answers with 503 for a while once the upstream keeps failing
+    #[serde(default)]
+    circuit_breaker: Option<breaker::CircuitBreakerConfig>,
+    /// requests each client address may send, answered with 429 beyond
+    #[serde(default)]
+    rate_limit: Option<ratelimit::RateLimitConfig>,
+    /// keeps upstream responses to GET and HEAD requests in memory
+    #[serde(default)]
+    cache: Option<cache::CacheConfig>,
+    /// order of the headers sent upstream: `client`, or `fixed` with a list
+    /// of header names to send first
+    #[serde(default)]
+    header_order: Option<header_order::HeaderOrderConfig>,
+    /// alerts when the share of 5xx responses over a rolling window crosses
+    /// a threshold
+    #[serde(default)]
+    error_budget: Option<alert::ErrorBudgetConfig>,
+}
+fn default_user_agent() -> String {
+    concat!("reproxy/", env!("CARGO_PKG_VERSION")).to_string()
 }
+
+fn default_http10_buffer() -> bytesize::ByteSize {
+    bytesize::ByteSize::mib(1)
+}
+
+fn default_max_redirects() -> usize {
+    10
+}
+
+fn default_drain_period() -> Duration {
+    Duration::from_secs(30)
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 enum ProxyHeaderConfig {
     Passthrough,
     Ignore,
 
+    Set {
+        set: String,
+    },
+
     Replace {
         #[serde(default)]
         r#match: String,
         #[serde(default)]
         replace: String,
+        #[serde(default)]
+        on_mismatch: OnMismatchConfig,
+        #[serde(default)]
+        default_value: Option<String>,
     },
+
+    /// `passthrough` or `ignore` spelled out, as null always means passthrough
+    Keyword(HeaderKeyword),
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HeaderKeyword {
+    Passthrough,
+    Ignore,
+}
+
+/// HTTP version of upstream requests.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum HttpVersion {
+    /// HTTP/2 where TLS negotiates it, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    /// HTTP/1.1 only, even where TLS would allow HTTP/2
+    Http1,
+    /// HTTP/2 without TLS, for upstreams known to speak it, such as gRPC
+    /// services; also used on https targets without negotiating
+    H2c,
+}
+
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum OnMismatchConfig {
+    #[default]
+    Reject,
+    Passthrough,
+    Drop,
+    DefaultValue,
+}
+
+/// Replace actions also apply to headers the request lacks, evaluated against
+/// an empty value, so they can create headers; a mismatch then only falls
+/// back to `DefaultValue` and is never rejected.
 enum HeaderAction {
     Passthrough,
     Ignore,
-    Replace { regex: Regex, replace: String },
+    Set(HeaderValue),
+    Replace {
+        regex: Regex,
+        replace: String,
+        on_mismatch: OnMismatch,
+    },
+}
+
+/// What a Replace action does with a header value its regex doesn't match.
+enum OnMismatch {
+    Reject,
+    Passthrough,
+    Drop,
+    DefaultValue(HeaderValue),
 }
 
 struct ProxyItem {
     name: String,
+    /// the rule as configured, compared field by field on reload
+    config: serde_json::Value,
+    description: Option<String>,
+    owner: Option<String>,
+    tags: Vec<String>,
     regex: Regex,
-    replace: String,
-    follow_redirect: bool,
-    header_actions: HashMap<String, HeaderAction>,
+    conditions: conditions::Conditions,
+    targets: Vec<Arc<Target>>,
+    fallback: Option<Arc<Target>>,
+    fallback_on: Vec<u16>,
+    login: Option<login::Login>,
+    balancer: Balancer,
+    /// target lists of previous configs, oldest first
+    draining: Vec<Draining>,
+    drain_period: Duration,
+    health_check: Option<health::HealthCheckConfig>,
+    breaker: Option<breaker::CircuitBreaker>,
+    rate_limit: Option<ratelimit::RateLimiter>,
+    cache: Option<Arc<cache::Cache>>,
+    /// shared by all requests of the rule so upstream connections are pooled
+    client: recycle::RecyclingClient,
+    /// pools connections to the targets on Unix sockets
+    unix_client: unix::UnixClient,
+    tls_server_name: Option<String>,
+    access_log_format: Option<String>,
+    preserve_host: bool,
+    x_forwarded_for: bool,
+    block_internal_targets: bool,
+    force_https: Option<scheme::ForceHttps>,
+    proxied_by: Option<HeaderValue>,
+    first_byte_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    max_response_header_size: Option<usize>,
+    buffer_response: Option<usize>,
+    max_response_size: Option<usize>,
+    sse_heartbeat: Option<Duration>,
+    error_format: ErrorFormat,
+    header_actions: HashMap<HeaderName, HeaderAction>,
     header_action_fallback: HeaderAction,
+    header_order: Option<header_order::HeaderOrder>,
+    response_header_actions: HashMap<HeaderName, HeaderAction>,
+    response_header_action_fallback: HeaderAction,
+    response_content_type: Option<content_type::ResponseContentType>,
+    cookies: cookie::CookieRewrite,
+}
+
+/// The routing state swapped as a whole on every config reload.
+struct ProxyTable {
+    items: Vec<ProxyItem>,
+    /// the config as loaded, served by the admin API
+    config: serde_json::Value,
+    buffered_memory: Arc<memory::Budget>,
+    cache_memory: Arc<memory::Budget>,
+    http10_buffer: usize,
+    /// by the name of the rule they watch
+    error_budgets: HashMap<String, alert::ErrorBudget>,
+    error_format: ErrorFormat,
+    trusted_proxies: client_ip::TrustedProxies,
+    host_guard: host_guard::HostGuard,
+    overrides: overrides::Overrides,
+    canonical_hosts: HashMap<String, canonical::CanonicalForm>,
+    /// recently requested urls and the index of the rule they matched
+    match_cache: Option<Mutex<lru::LruCache<String, Option<usize>>>>,
+}
+
+/// Compiles a pattern of rule `name` within the configured size limits, so a
+/// pathological pattern fails validation instead of eating startup memory.
+fn compile_regex(config: &Config, name: &str, pattern: &str) -> anyhow::Result<Regex> {
+    let mut builder = regex::RegexBuilder::new(pattern);
+    if let Some(limit) = config.regex_size_limit {
+        builder.size_limit(limit.as_u64() as usize);
+    }
+    if let Some(limit) = config.regex_dfa_size_limit {
+        builder.dfa_size_limit(limit.as_u64() as usize);
+    }
+    builder.build().map_err(|err| match err {
+        regex::Error::CompiledTooBig(limit) => anyhow::anyhow!(
+            "{name}: pattern `{pattern}` exceeds the regex size limit of {}",
+            bytesize::ByteSize(limit as u64).to_string_as(true)
+        ),
+        err => anyhow::anyhow!("{name}: invalid pattern `{pattern}`: {err}"),
+    })
 }
 
-fn parse_config(config: &Config) -> anyhow::Result<Vec<ProxyItem>> {
+impl ProxyTable {
+    fn find(
+        &self,
+        url: &str,
+        host: &str,
+        headers: &HeaderMap,
+        client: IpAddr,
+    ) -> Option<&ProxyItem> {
+        // the cache only remembers the first rule whose pattern matches, the
+        // request conditions are checked on every lookup
+        let cached = self
+            .match_cache
+            .as_ref()
+            .and_then(|cache| cache.lock().unwrap().get(url).copied());
+        let start = match cached {
+            Some(index) => index?,
+            None => {
+                let index = self.items.iter().position(|item| item.regex.is_match(url));
+                if let Some(cache) = &self.match_cache {
+                    cache.lock().unwrap().put(url.to_string(), index);
+                }
+                index?
+            }
+        };
+        let first = &self.items[start];
+        if first.conditions.matches(host, headers, client) {
+            return Some(first);
+        }
+        self.items[start + 1..]
+            .iter()
+            .find(|item| item.regex.is_match(url) && item.conditions.matches(host, headers, client))
+    }
+}
+
+fn parse_config(config: &Config) -> anyhow::Result<ProxyTable> {
+    let mut rules: Vec<_> = config.items.iter().collect();
+    // a stable sort keeps config order among rules of the same priority
+    rules.sort_by_key(|(_, item)| std::cmp::Reverse(item.priority));
     let mut items = Vec::new();
-    for (name, item) in config.0.iter() {
-        let re = Regex::new(&item.r#match)?;
-
-        let mut actions = HashMap::new();
-        let mut header_action_fallback = HeaderAction::Ignore;
-        for (header_name, config) in item.headers.iter() {
-            let action = match config {
-                ProxyHeaderConfig::Passthrough => HeaderAction::Passthrough,
-                ProxyHeaderConfig::Ignore => HeaderAction::Ignore,
-                ProxyHeaderConfig::Replace { r#match, replace } => HeaderAction::Replace {
-                    regex: Regex::new(r#match)?,
-                    replace: replace.to_string(),
-                },
-            };
-            if header_name == "$default" {
-                header_action_fallback = action;
-            } else {
-                actions.insert(header_name.to_lowercase().clone(), action);
+    let mut error_budgets = HashMap::new();
+    let cache_memory = memory::Budget::new(
+        "cache",
+        config.max_cache_memory.map(|size| size.as_u64() as usize),
+    );
+    for (name, item) in rules {
+        if let Some(budget) = &item.error_budget {
+            let budget =
+                alert::ErrorBudget::new(budget).map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+            error_budgets.insert(name.clone(), budget);
+        }
+        let block_internal_targets = config.block_internal_targets && !item.allow_internal_targets;
+        let re = compile_regex(config, name, &item.r#match)?;
+        let conditions = conditions::Conditions {
+            hosts: item.hosts.iter().map(|host| host.to_lowercase()).collect(),
+            cookies: item
+                .cookies
+                .iter()
+                .map(|(cookie, pattern)| {
+                    let pattern = pattern
+                        .as_ref()
+                        .map(|pattern| compile_regex(config, name, pattern))
+                        .transpose()?;
+                    anyhow::Ok((cookie.clone(), pattern))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            negotiation: [
+                (header::ACCEPT, &item.accept),
+                (header::ACCEPT_LANGUAGE, &item.accept_language),
+                (header::CONTENT_TYPE, &item.content_type),
+            ]
+            .into_iter()
+            .filter_map(|(header_name, pattern)| Some((header_name, pattern.as_ref()?)))
+            .map(|(header_name, pattern)| Ok((header_name, compile_regex(config, name, pattern)?)))
+            .collect::<anyhow::Result<_>>()?,
+            clients: item
+                .clients
+                .iter()
+                .map(|entry| client_ip::parse_network(entry))
+                .collect::<Result<_, _>>()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+        };
+        let targets = balance::targets(item.target.as_ref(), &item.targets)
+            .map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+        if let Some(format) = &item.access_log_format {
+            format
+                .parse::<access_log::AccessFormat>()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+        }
+        if item.fallback_target.is_none() && !item.fallback_on.is_empty() {
+            anyhow::bail!("{name}: `fallback_on` without a `fallback_target`");
+        }
+        for target in targets.iter().chain(item.fallback_target.iter()) {
+            scheme::validate_template(target, item.force_https)
+                .and_then(|_| authority::validate_template(target))
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+        }
+
+        let (actions, header_action_fallback) =
+            parse_header_actions(config, name, &item.headers, HeaderAction::Ignore)?;
+        let (response_actions, response_header_action_fallback) = parse_header_actions(
+            config,
+            name,
+            &item.response_headers,
+            HeaderAction::Passthrough,
+        )?;
+        let redirect = item.follow_redirect.then(|| redirect::RedirectRules {
+            max_hops: item.max_redirects,
+            allowlist: Arc::new(
+                item.redirect_allowlist
+                    .iter()
+                    .map(|host| host.to_lowercase())
+                    .collect(),
+            ),
+            block_internal: block_internal_targets,
+            https_only: item.force_https.is_some(),
+            credentials: item.redirect_credentials,
+        });
+        // sent unless the client's own User-Agent is forwarded
+        let user_agent = item
+            .user_agent
+            .clone()
+            .unwrap_or_else(|| config.user_agent.clone());
+        let resolver = (!item.pinned_hosts.is_empty()).then(|| {
+            Arc::new(pin::PinnedResolver::new(
+                &item.pinned_hosts,
+                block_internal_targets,
+            ))
+        });
+        let server_name_resolver = match &item.tls_server_name {
+            Some(server_name) => {
+                let mut hosts = targets
+                    .iter()
+                    .chain(item.fallback_target.iter())
+                    .map(|target| probe::static_authority(target).map(|(host, _)| host));
+                let host = hosts.next().flatten();
+                let Some(host) =
+                    host.filter(|host| hosts.all(|other| other.as_ref() == Some(host)))
+                else {
+                    anyhow::bail!("{name}: `tls_server_name` needs targets with one fixed host");
+                };
+                Some(Arc::new(pin::ServerNameResolver::new(
+                    server_name,
+                    &host,
+                    resolver.clone(),
+                    block_internal_targets,
+                )))
             }
+            None => None,
+        };
+        let local_address = item.local_address;
+        let ca_certs = item
+            .ca_bundle
+            .as_deref()
+            .map(tls::read_certs)
+            .transpose()
+            .map_err(|err| anyhow::anyhow!("{name}: {err}"))?
+            .unwrap_or_default();
+        if item.insecure_skip_verify && !item.tls_pins.is_empty() {
+            anyhow::bail!("{name}: `insecure_skip_verify` and `tls_pins` are exclusive");
         }
+        let roots =
+            tls::upstream_roots(&ca_certs).map_err(|err| anyhow::anyhow!("{name}: {err}"))?;
+        let verifier: Arc<dyn rustls::client::ServerCertVerifier> = if item.insecure_skip_verify {
+            Arc::new(tls::NoVerifier)
+        } else if !item.tls_pins.is_empty() {
+            Arc::new(
+                spki::pinned_verifier(&item.tls_pins, roots)
+                    .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+            )
+        } else {
+            Arc::new(rustls::client::WebPkiVerifier::new(roots, None))
+        };
+        let alpn: &[&[u8]] = match item.http_version {
+            HttpVersion::Auto => &[b"h2", b"http/1.1"],
+            HttpVersion::Http1 => &[b"http/1.1"],
+            HttpVersion::H2c => &[b"h2"],
+        };
+        let tls_config = connector::tls_config(verifier, alpn);
+        let connect_timeout = item.connect_timeout.or(config.connect_timeout);
+        let settings = upstream::Settings {
+            user_agent: (!user_agent.is_empty())
+                .then(|| HeaderValue::from_str(&user_agent))
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: `user_agent`: {err}"))?,
+            request_timeout: item.request_timeout.or(config.request_timeout),
+            pool_idle_timeout: item.pool_idle_timeout,
+            http2_only: item.http_version == HttpVersion::H2c,
+            redirect,
+        };
+        let unix_client = unix::UnixClient::new(&settings, connect_timeout);
+        let settings = Arc::new(settings);
+        let resolver: Option<Arc<dyn reqwest::dns::Resolve>> =
+            if let Some(resolver) = server_name_resolver {
+                Some(resolver)
+            } else if let Some(resolver) = resolver {
+                Some(resolver)
+            } else if block_internal_targets {
+                Some(Arc::new(ssrf::PublicOnlyResolver))
+            } else {
+                None
+            };
+        let resolver: Arc<dyn reqwest::dns::Resolve> =
+            Arc::new(timing::TimedResolver::new(resolver));
+        let build_client = move || {
+            let connector = connector::Connector::new(
+                resolver.clone(),
+                tls_config.clone(),
+                local_address,
+                connect_timeout,
+            );
+            upstream::Client::new(connector, settings.clone())
+        };
+
         items.push(ProxyItem {
             name: name.clone(),
+            config: serde_json::to_value(item)?,
+            description: item.description.clone(),
+            owner: item.owner.clone(),
+            tags: item.tags.clone(),
             regex: re,
-            replace: item.target.to_string(),
-            follow_redirect: item.follow_redirect,
+            conditions,
+            targets: targets.into_iter().map(Target::new).collect(),
+            fallback: item.fallback_target.clone().map(Target::new),
+            fallback_on: item.fallback_on.clone(),
+            login: item
+                .login
+                .as_ref()
+                .map(login::Login::new)
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+            balancer: Balancer::new(item.balance),
+            draining: Vec::new(),
+            drain_period: item.drain_period,
+            health_check: item.health_check.clone(),
+            breaker: item
+                .circuit_breaker
+                .clone()
+                .map(breaker::CircuitBreaker::new),
+            rate_limit: item
+                .rate_limit
+                .as_ref()
+                .map(ratelimit::RateLimiter::new)
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+            cache: item
+                .cache
+                .as_ref()
+                .map(|cache| cache::Cache::new(cache, cache_memory.clone()))
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?
+                .map(Arc::new),
+            client: recycle::RecyclingClient::new(
+                Box::new(build_client),
+                item.max_connection_age,
+                item.max_connection_requests,
+            ),
+            unix_client,
+            tls_server_name: item.tls_server_name.clone(),
+            access_log_format: item.access_log_format.clone(),
+            preserve_host: item.preserve_host,
+            x_forwarded_for: item.x_forwarded_for,
+            block_internal_targets,
+            force_https: item.force_https,
+            proxied_by: item
+                .proxied_by
+                .as_ref()
+                .or(config.proxied_by.as_ref())
+                .map(|value| HeaderValue::from_str(value))
+                .transpose()?,
+            first_byte_timeout: item.first_byte_timeout,
+            read_timeout: item.read_timeout.or(config.read_timeout),
+            max_response_header_size: item
+                .max_response_header_size
+                .map(|size| size.as_u64() as usize),
+            buffer_response: item.buffer_response.map(|size| size.as_u64() as usize),
+            max_response_size: item.max_response_size.map(|size| size.as_u64() as usize),
+            sse_heartbeat: item.sse_heartbeat,
+            error_format: item.error_format.unwrap_or(config.error_format),
             header_actions: actions,
             header_action_fallback,
+            header_order: item
+                .header_order
+                .as_ref()
+                .map(header_order::HeaderOrder::new)
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+            response_header_actions: response_actions,
+            response_header_action_fallback,
+            response_content_type: item
+                .response_content_type
+                .as_ref()
+                .map(content_type::ResponseContentType::new)
+                .transpose()
+                .map_err(|err| anyhow::anyhow!("{name}: {err}"))?,
+            cookies: cookie::CookieRewrite::new(&item.cookie_domain, &item.cookie_path),
         });
     }
-    Ok(items)
+    Ok(ProxyTable {
+        items,
+        config: serde_json::to_value(config)?,
+        buffered_memory: memory::Budget::new(
+            "buffered",
+            config
+                .max_buffered_memory
+                .map(|size| size.as_u64() as usize),
+        ),
+        cache_memory,
+        http10_buffer: config.http10_buffer.as_u64() as usize,
+        error_budgets,
+        error_format: config.error_format,
+        trusted_proxies: config.trusted_proxies.clone(),
+        host_guard: host_guard::HostGuard {
+            allowed_hosts: config.allowed_hosts.clone(),
+            reject_authority_mismatch: config.reject_authority_mismatch,
+        },
+        overrides: overrides::Overrides::new(&config.overrides)?,
+        canonical_hosts: config
+            .canonical_hosts
+            .iter()
+            .map(|(domain, form)| (domain.to_lowercase(), *form))
+            .collect(),
+        match_cache: std::num::NonZeroUsize::new(config.match_cache_size)
+            .map(|size| Mutex::new(lru::LruCache::new(size))),
+    })
+}
+
+/// Resolves the header actions of rule `name`; the `$default` entry replaces
+/// `fallback` as the action for headers without one of their own.
+fn parse_header_actions(
+    config: &Config,
+    name: &str,
+    headers: &HashMap<String, ProxyHeaderConfig>,
+    mut fallback: HeaderAction,
+) -> anyhow::Result<(HashMap<HeaderName, HeaderAction>, HeaderAction)> {
+    let mut actions = HashMap::new();
+    for (header_name, header_config) in headers.iter() {
+        let invalid =
+            |err: &dyn std::fmt::Display| anyhow::anyhow!("{name}: header `{header_name}`: {err}");
+        let action = match header_config {
+            ProxyHeaderConfig::Passthrough | ProxyHeaderConfig::Keyword(HeaderKeyword::Passthrough) => {
+                HeaderAction::Passthrough
+            }
+            ProxyHeaderConfig::Ignore | ProxyHeaderConfig::Keyword(HeaderKeyword::Ignore) => {
+                HeaderAction::Ignore
+            }
+            ProxyHeaderConfig::Set { set } => {
+                HeaderAction::Set(HeaderValue::from_str(set).map_err(|err| invalid(&err))?)
+            }
+            ProxyHeaderConfig::Replace {
+                r#match,
+                replace,
+                on_mismatch,
+                default_value,
+            } => HeaderAction::Replace {
+                regex: compile_regex(config, name, r#match)?,
+                replace: replace.to_string(),
+                on_mismatch: match (on_mismatch, default_value) {
+                    (OnMismatchConfig::DefaultValue, Some(value)) => OnMismatch::DefaultValue(
+                        HeaderValue::from_str(value).map_err(|err| invalid(&err))?,
+                    ),
+                    (OnMismatchConfig::DefaultValue, None) => anyhow::bail!(
+                        "{name}: header `{header_name}` uses `on_mismatch: default_value` without a `default_value`"
+                    ),
+                    (_, Some(_)) => anyhow::bail!(
+                        "{name}: header `{header_name}` sets `default_value` without `on_mismatch: default_value`"
+                    ),
+                    (OnMismatchConfig::Reject, None) => OnMismatch::Reject,
+                    (OnMismatchConfig::Passthrough, None) => OnMismatch::Passthrough,
+                    (OnMismatchConfig::Drop, None) => OnMismatch::Drop,
+                },
+            },
+        };
+        if header_name == "$default" {
+            fallback = action;
+        } else {
+            let header_name =
+                HeaderName::from_bytes(header_name.as_bytes()).map_err(|err| invalid(&err))?;
+            actions.insert(header_name, action);
+        }
+    }
+    Ok((actions, fallback))
+}
+
+impl ProxyItem {
+    /// Applies the rule's response header actions to upstream `headers`.
+    fn rewrite_response_headers(&self, headers: &mut HeaderMap) -> anyhow::Result<()> {
+        let upstream = std::mem::take(headers);
+        for (name, value) in upstream.iter() {
+            let action = self
+                .response_header_actions
+                .get(name)
+                .unwrap_or(&self.response_header_action_fallback);
+            match action {
+                HeaderAction::Passthrough => {
+                    headers.append(name, value.clone());
+                }
+                HeaderAction::Replace {
+                    regex: re,
+                    replace,
+                    on_mismatch,
+                } => {
+                    if let Some(text) = value.to_str().ok().filter(|text| re.is_match(text)) {
+                        let replaced = re.replace(text, replace);
+                        headers.append(name, HeaderValue::from_str(&replaced)?);
+                        continue;
+                    }
+                    match on_mismatch {
+                        OnMismatch::Reject => {
+                            return Err(errors::HeaderMismatch(name.to_string()).into())
+                        }
+                        OnMismatch::Passthrough => {
+                            headers.append(name, value.clone());
+                        }
+                        OnMismatch::Drop => {}
+                        OnMismatch::DefaultValue(default) => {
+                            headers.append(name, default.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (name, action) in self.response_header_actions.iter() {
+            match action {
+                HeaderAction::Set(value) => {
+                    headers.append(name, value.clone());
+                }
+                HeaderAction::Replace {
+                    regex: re,
+                    replace,
+                    on_mismatch,
+                } if !upstream.contains_key(name) => {
+                    if re.is_match("") {
+                        let value = re.replace("", replace);
+                        if !value.is_empty() {
+                            headers.append(name, HeaderValue::from_str(&value)?);
+                        }
+                    } else if let OnMismatch::DefaultValue(default) = on_mismatch {
+                        headers.append(name, default.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn target_url<'a>(&self, url: &'a str, target: &Target) -> std::borrow::Cow<'a, str> {
+        let target_url = self.regex.replace(url, |captures: &regex::Captures<'_>| {
+            authority::expand(captures, &target.template)
+        });
+        if self.force_https == Some(scheme::ForceHttps::Upgrade) {
+            if let Some(upgraded) = scheme::upgrade(&target_url) {
+                return upgraded.into();
+            }
+        }
+        target_url
+    }
+
+    /// Addresses `subrequest` to `tls_server_name` so TLS uses that name,
+    /// while its resolver still connects to the target's host, which is kept
+    /// as the Host header.
+    fn use_server_name(&self, subrequest: &mut reqwest::Request) -> anyhow::Result<()> {
+        let Some(server_name) = &self.tls_server_name else {
+            return Ok(());
+        };
+        let url = subrequest.url_mut();
+        if url.scheme() != "https" {
+            return Ok(());
+        }
+        let host = url.host_str().unwrap_or_default();
+        let authority = match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        };
+        url.set_host(Some(server_name))?;
+        subrequest
+            .headers_mut()
+            .insert(header::HOST, HeaderValue::from_str(&authority)?);
+        Ok(())
+    }
+
+    /// Picks among the healthy targets, or among all of them when none is.
+    fn pick_target(&self, client_ip: IpAddr) -> &Arc<Target> {
+        let now = std::time::Instant::now();
+        let targets = self
+            .draining
+            .iter()
+            .find(|draining| now < draining.until)
+            .map_or(&self.targets, |draining| &draining.targets);
+        if targets.iter().all(|target| target.is_healthy()) {
+            return &targets[self.balancer.pick(targets.len(), client_ip)];
+        }
+        let healthy: Vec<_> = targets
+            .iter()
+            .filter(|target| target.is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            return &targets[self.balancer.pick(targets.len(), client_ip)];
+        }
+        healthy[self.balancer.pick(healthy.len(), client_ip)]
+    }
+}
+
+fn load_config(path: &str) -> anyhow::Result<Config> {
+    Ok(serde_yaml::from_reader(std::fs::File::open(path)?)?)
 }
 
 struct AppState {
-    proxy_items: Vec<ProxyItem>,
+    table: RwLock<Arc<ProxyTable>>,
+    /// health checks of the current table, aborted when it is replaced
+    health_checks: Mutex<tokio::task::JoinSet<()>>,
 }
 
 #[axum::debug_handler]
 async fn handle_request(
-    Host(host): Host,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
     mut request: Request<Body>,
 ) -> Response<Body> {
-    return handle(&mut request, host, state)
-        .await
+    let request_id = errors::request_id(request.headers());
+    let table = state.table.read().unwrap().clone();
+    let remote = client_ip::Remote {
+        peer,
+        client: client_ip::resolve(peer, request.headers(), &table.trusted_proxies),
+    };
+    let host = client_ip::resolve_host(
+        peer,
+        request.headers(),
+        request.uri(),
+        &table.trusted_proxies,
+    );
+    // HTTP/2 requests carry an absolute uri, only its path is matched
+    let url = host.clone().unwrap_or_default()
+        + request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+    let refusal = match &host {
+        Some(host) => table
+            .host_guard
+            .check(host, request.uri(), request.headers()),
+        None => Some((
+            StatusCode::BAD_REQUEST,
+            "missing_host",
+            "the request names no host",
+        )),
+    };
+    if let Some((status, code, message)) = refusal {
+        let response =
+            errors::respond(table.error_format, status, code, message, &request_id, None);
+        access_log::record(&request, remote, &url, &Default::default(), &response);
+        return response;
+    }
+    let host = host.unwrap_or_default();
+    let mut entry = access_log::AccessEntry::default();
+    // a panic fails this request only; the hook has logged it already
+    let handled = std::panic::AssertUnwindSafe(handle(
+        &mut request,
+        host,
+        &url,
+        remote,
+        &table,
+        &request_id,
+        &mut entry,
+    ))
+    .catch_unwind()
+    .await;
+    let response = handled
+        .unwrap_or_else(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(anyhow::anyhow!("handler panicked: {message}"))
+        })
         .unwrap_or_else(|err| {
             tracing::error!(
                 method = ?request.method(),
-                requested = request.uri().to_string(),
+                requested = url,
                 error = ?err,
                 status = 500
             );
-            Response::builder()
-                .status(500)
-                .body(axum::body::Body::empty())
-                .unwrap()
+            errors::respond(
+                table.error_format,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "the proxy failed to handle the request",
+                &request_id,
+                None,
+            )
         });
+    if let Some((rule, budget)) = entry
+        .matched
+        .as_ref()
+        .and_then(|rule| Some((rule, table.error_budgets.get(rule)?)))
+    {
+        budget.record(rule, response.status());
+    }
+    access_log::record(&request, remote, &url, &entry, &response);
+    return response;
 
     async fn handle(
         request: &mut Request<Body>,
         host: String,
-        state: Arc<AppState>,
+        url: &str,
+        remote: client_ip::Remote,
+        table: &ProxyTable,
+        request_id: &str,
+        entry: &mut access_log::AccessEntry,
     ) -> anyhow::Result<Response<Body>> {
-        let url = host + &request.uri().to_string();
-        let matched_item = state
-            .proxy_items
-            .iter()
-            .find(|item| item.regex.is_match(&url));
+        let path_and_query = &url[host.len()..];
+        if let Some(response) = canonical::redirect(&table.canonical_hosts, &host, path_and_query)
+            .or_else(|| {
+                table
+                    .overrides
+                    .respond(request.method(), &host, request.uri().path())
+            })
+        {
+            return Ok(response);
+        }
+        let matched_item = table.find(url, &host, request.headers(), remote.client);
         if let Some(item) = matched_item {
-            let target_url = item.regex.replace(&url, &item.replace);
-            let client = reqwest::Client::builder()
-                .redirect(if item.follow_redirect {
-                    reqwest::redirect::Policy::limited(10)
-                } else {
-                    reqwest::redirect::Policy::none()
-                })
-                .build()?;
-            let mut builder = client.request(request.method().clone(), target_url.as_ref());
+            entry.matched = Some(item.name.clone());
+            entry.owner = item.owner.clone();
+            entry.tags = (!item.tags.is_empty()).then(|| item.tags.join(","));
+            entry.format = item.access_log_format.clone();
+            if let Some(Err(retry_after)) = item
+                .rate_limit
+                .as_ref()
+                .map(|limiter| limiter.acquire(remote.client))
+            {
+                let mut response = errors::respond(
+                    item.error_format,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate_limited",
+                    "too many requests from this client",
+                    request_id,
+                    Some(&item.name),
+                );
+                response.headers_mut().insert(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(retry_after.as_secs_f64().ceil() as u64),
+                );
+                return Ok(response);
+            }
+            if let Some(login) = &item.login {
+                if let Some(response) = login.check(request, &item.name).await? {
+                    return Ok(response);
+                }
+            }
+            let cached = item
+                .cache
+                .as_ref()
+                .and_then(|cache| Some((cache, cache::Cache::key(request, url)?)));
+            let mut stale = None;
+            if let Some((cache, key)) = &cached {
+                match cache.lookup(key, request.headers()) {
+                    cache::Lookup::Fresh(mut response) => {
+                        response
+                            .headers_mut()
+                            .insert("x-cache", HeaderValue::from_static("HIT"));
+                        return Ok(response);
+                    }
+                    // a conditional request of the client is left to the upstream
+                    cache::Lookup::Stale(entry)
+                        if !request.headers().contains_key(header::IF_NONE_MATCH)
+                            && !request.headers().contains_key(header::IF_MODIFIED_SINCE) =>
+                    {
+                        stale = Some(entry)
+                    }
+                    _ => {}
+                }
+            }
+            let admission = match item
+                .breaker
+                .as_ref()
+                .map(|breaker| breaker.admit(&item.name))
+            {
+                Some(Ok(admission)) => Some(admission),
+                Some(Err(retry_after)) => {
+                    let mut response = errors::respond(
+                        item.error_format,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "circuit_open",
+                        "the upstream is failing, requests are paused",
+                        request_id,
+                        Some(&item.name),
+                    );
+                    response.headers_mut().insert(
+                        header::RETRY_AFTER,
+                        HeaderValue::from(retry_after.as_secs().max(1)),
+                    );
+                    return Ok(response);
+                }
+                None => None,
+            };
+            let target = item.pick_target(remote.client);
+            let mut target_url = item.target_url(url, target);
+            let (mut socket, address) = unix::split(&target_url);
+            let target_address = match authority::parse(&address) {
+                Ok(address) => address,
+                Err(err) => {
+                    tracing::error!(
+                        method = ?request.method(),
+                        requested = url,
+                        matched = item.name,
+                        error = %err,
+                    );
+                    return Ok(errors::respond(
+                        item.error_format,
+                        StatusCode::BAD_GATEWAY,
+                        "invalid_target",
+                        "the rewritten target is not a valid url",
+                        request_id,
+                        Some(&item.name),
+                    ));
+                }
+            };
+            let client = item.client.get();
+            let mut subrequest = reqwest::Request::new(request.method().clone(), target_address);
+            let headers = subrequest.headers_mut();
             for (header_name, header_value) in request.headers().iter() {
-                let name = header_name.as_str().to_lowercase();
+                let name = header_name.as_str();
                 let action = item
                     .header_actions
-                    .get(&name)
+                    .get(header_name)
                     .unwrap_or(&item.header_action_fallback);
                 match action {
                     HeaderAction::Passthrough => {
-                        builder = builder.header(header_name, header_value)
+                        headers.append(header_name, header_value.clone());
                     }
-                    HeaderAction::Replace { regex: re, replace } => {
+                    HeaderAction::Replace {
+                        regex: re,
+                        replace,
+                        on_mismatch,
+                    } => {
                         let value = header_value.to_str()?;
                         if re.is_match(value) {
-                            builder =
-                                builder.header(header_name, re.replace(value, replace).as_ref());
-                        } else {
+                            let value = HeaderValue::from_str(&re.replace(value, replace))?;
+                            headers.append(header_name, value);
+                            continue;
+                        }
+                        match on_mismatch {
+                            OnMismatch::Reject => {
+                                tracing::error!(
+                                    method = ?request.method(),
+                                    requested = url,
+                                    matched = item.name,
+                                    status = 400,
+                                    unmatched_header = name
+                                );
+                                return Ok(errors::respond(
+                                    item.error_format,
+                                    StatusCode::BAD_REQUEST,
+                                    "header_mismatch",
+                                    &format!("header `{name}` does not match the expected pattern"),
+                                    request_id,
+                                    Some(&item.name),
+                                ));
+                            }
+                            OnMismatch::Passthrough => {
+                                headers.append(header_name, header_value.clone());
+                            }
+                            OnMismatch::Drop => {}
+                            OnMismatch::DefaultValue(default) => {
+                                headers.append(header_name, default.clone());
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            for (name, action) in item.header_actions.iter() {
+                match action {
+                    HeaderAction::Set(value) => {
+                        headers.append(name, value.clone());
+                    }
+                    HeaderAction::Replace {
+                        regex: re,
+                        replace,
+                        on_mismatch,
+                    } if !request.headers().contains_key(name) => {
+                        if re.is_match("") {
+                            let value = re.replace("", replace);
+                            if !value.is_empty() {
+                                headers.append(name, HeaderValue::from_str(&value)?);
+                            }
+                        } else if let OnMismatch::DefaultValue(default) = on_mismatch {
+                            headers.append(name, default.clone());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // an empty body is left out so the request can be replayed
+            if !request.body().is_end_stream() {
+                // the body is streamed, so its length is only sent in the
+                // header; HTTP/3 bodies come without one but the header's
+                let length = match request.body().size_hint().exact() {
+                    Some(length) => Some(HeaderValue::from(length)),
+                    None => request.headers().get(header::CONTENT_LENGTH).cloned(),
+                };
+                if let Some(length) = length {
+                    headers.insert(header::CONTENT_LENGTH, length);
+                }
+                *subrequest.body_mut() = Some(std::mem::take(request.body_mut()).into());
+            }
+            item.use_server_name(&mut subrequest)?;
+            let client_upgrade =
+                websocket::is_upgrade(request.headers()).then(|| hyper::upgrade::on(&mut *request));
+            if client_upgrade.is_some() {
+                for name in websocket::HANDSHAKE_HEADERS.iter() {
+                    subrequest.headers_mut().remove(name);
+                    for value in request.headers().get_all(name).iter() {
+                        subrequest.headers_mut().append(name, value.clone());
+                    }
+                }
+            }
+            if let Some(proxied_by) = &item.proxied_by {
+                subrequest
+                    .headers_mut()
+                    .insert("x-proxied-by", proxied_by.clone());
+            }
+            if item.preserve_host {
+                subrequest
+                    .headers_mut()
+                    .insert(header::HOST, HeaderValue::from_str(&host)?);
+            }
+            if item.x_forwarded_for {
+                let forwarded_for = client_ip::forwarded_for(
+                    remote.peer,
+                    request.headers(),
+                    &table.trusted_proxies,
+                );
+                subrequest
+                    .headers_mut()
+                    .insert("x-forwarded-for", HeaderValue::from_str(&forwarded_for)?);
+            }
+            if let Some(stale) = &stale {
+                subrequest.headers_mut().extend(stale.conditions());
+            }
+            let replay = item
+                .fallback
+                .as_ref()
+                .and_then(|fallback| Some((fallback, subrequest.try_clone()?)));
+            if let Some(order) = &item.header_order {
+                let url = subrequest.url().clone();
+                order.apply(subrequest.headers_mut(), request.headers(), &url);
+            }
+            let mut cutoff = target.subscribe();
+            let attempts = &mut entry.attempts;
+            let started = std::time::Instant::now();
+            let mut timeline = timing::Timeline::start();
+            let mut subresp = forward(
+                &client,
+                item,
+                subrequest,
+                socket.as_deref(),
+                &timeline,
+                &mut cutoff,
+            )
+            .await;
+            attempts.record(&target_url, started, &subresp, |subresp| {
+                subresp.status().as_u16()
+            });
+            if let Some((fallback, mut subrequest)) = replay {
+                let failed = subresp.as_ref().map_or(true, |subresp| {
+                    item.fallback_on.contains(&subresp.status().as_u16())
+                });
+                if failed {
+                    target_url = item.target_url(url, fallback);
+                    cutoff = fallback.subscribe();
+                    let started = std::time::Instant::now();
+                    timeline = timing::Timeline::start();
+                    let address;
+                    (socket, address) = unix::split(&target_url);
+                    subresp = match authority::parse(&address) {
+                        Ok(address) => {
+                            *subrequest.url_mut() = address;
+                            item.use_server_name(&mut subrequest)?;
+                            if let Some(order) = &item.header_order {
+                                let url = subrequest.url().clone();
+                                order.apply(subrequest.headers_mut(), request.headers(), &url);
+                            }
+                            forward(
+                                &client,
+                                item,
+                                subrequest,
+                                socket.as_deref(),
+                                &timeline,
+                                &mut cutoff,
+                            )
+                            .await
+                        }
+                        Err(err) => Err(err.into()),
+                    };
+                    attempts.record(&target_url, started, &subresp, |subresp| {
+                        subresp.status().as_u16()
+                    });
+                }
+            }
+            entry.upstream_time = Some(started.elapsed());
+            entry.forwarded = Some(target_url.to_string());
+            if let (Some(breaker), Some(admission)) = (&item.breaker, admission) {
+                let success = subresp
+                    .as_ref()
+                    .is_ok_and(|subresp| !subresp.status().is_server_error());
+                breaker.record(&item.name, admission, success);
+            }
+            let mut subresp = match subresp {
+                Ok(subresp) => subresp,
+                Err(err) => {
+                    let (status, code, message) = errors::classify(&err);
+                    tracing::error!(
+                        method = ?request.method(),
+                        requested = url,
+                        matched = item.name,
+                        forwarded = target_url.as_ref(),
+                        error = ?err,
+                    );
+                    return Ok(errors::respond(
+                        item.error_format,
+                        status,
+                        code,
+                        message,
+                        request_id,
+                        Some(&item.name),
+                    ));
+                }
+            };
+            if let (Some(stale), Some((cache, _))) = (stale, &cached) {
+                if subresp.status() == StatusCode::NOT_MODIFIED {
+                    let mut response = cache.revalidated(stale, subresp.headers());
+                    response
+                        .headers_mut()
+                        .insert("x-cache", HeaderValue::from_static("REVALIDATED"));
+                    return Ok(response);
+                }
+            }
+
+            let mut builder = Response::builder().status(subresp.status());
+            let headers = builder.headers_mut().unwrap();
+            *headers = std::mem::take(subresp.headers_mut());
+            if let (StatusCode::SWITCHING_PROTOCOLS, Some(client_upgrade)) =
+                (subresp.status(), client_upgrade)
+            {
+                websocket::tunnel(client_upgrade, subresp, cutoff);
+                return Ok(builder.body(Body::empty())?);
+            }
+            // HTTP/1.0 clients know neither chunked bodies nor persistent
+            // connections by default, so bodies get a length where possible
+            let downgrade = request.version() == Version::HTTP_10;
+            if downgrade {
+                downgrade::strip_hop_headers(headers);
+            }
+            let unknown_length =
+                downgrade && downgrade::needs_length(request.method(), subresp.status(), headers);
+            let is_event_stream = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("text/event-stream"));
+            let store = match cached {
+                Some((cache, key)) => {
+                    let store =
+                        cache
+                            .freshness(subresp.status(), headers)
+                            .map(|fresh_for| cache::Store {
+                                cache: cache.clone(),
+                                key: cache.variant_key(key, headers, request.headers()),
+                                status: subresp.status(),
+                                headers: headers.clone(),
+                                fresh_for,
+                            });
+                    headers.insert("x-cache", HeaderValue::from_static("MISS"));
+                    store
+                }
+                None => None,
+            };
+            let length = subresp.content_length();
+            let body = drain::until_cutoff(subresp.bytes_stream(), cutoff);
+            let body = timeout::between_reads(body, item.read_timeout);
+            let body = timing::report(body, length, timeline, request_id, &item.name, &target_url);
+            let body = buffer::cap(body, item.max_response_size, item.name.clone());
+            let body = cache::tee(body, store);
+            let buffer_limit = item
+                .buffer_response
+                .or(unknown_length.then_some(table.http10_buffer));
+            let body = match (item.sse_heartbeat, buffer_limit) {
+                (Some(interval), _) if is_event_stream => {
+                    axum::body::Body::wrap_stream(heartbeat::inject(body, interval))
+                }
+                (_, Some(limit)) => {
+                    match buffer::collect(body, limit, &table.buffered_memory, headers).await {
+                        Ok(body) => body,
+                        Err(err) => {
+                            let (status, code, message) = errors::classify(&err);
                             tracing::error!(
                                 method = ?request.method(),
                                 requested = url,
                                 matched = item.name,
-                                status = 400,
-                                unmatched_header = name
+                                forwarded = target_url.as_ref(),
+                                error = ?err,
                             );
-                            return Ok(Response::builder()
-                                .status(400)
-                                .body(axum::body::Body::empty())?);
+                            return Ok(errors::respond(
+                                item.error_format,
+                                status,
+                                code,
+                                message,
+                                request_id,
+                                Some(&item.name),
+                            ));
                         }
                     }
-                    _ => {}
                 }
+                _ => axum::body::Body::wrap_stream(body),
+            };
+            if unknown_length && !headers.contains_key(header::CONTENT_LENGTH) {
+                downgrade::close(headers);
+                // hyper would answer a 1.1 response with keep-alive
+                builder = builder.version(Version::HTTP_10);
             }
-            let subrequest = builder.body(std::mem::take(request.body_mut())).build()?;
-            let mut subresp = client.execute(subrequest).await.map_err(|err| {
-                tracing::error!(
-                    method = ?request.method(),
-                    requested = url,
-                    matched = item.name,
-                    forwarded = target_url.as_ref(),
-                    error = ?err,
-                );
-                err
-            })?;
-
-            tracing::info!(
-                method = ?request.method(),
-                requested = url,
-                matched = item.name,
-                forwarded = target_url.as_ref(),
-                status = subresp.status().as_u16(),
-            );
-            let mut builder = Response::builder().status(subresp.status());
-            *builder.headers_mut().unwrap() = std::mem::take(subresp.headers_mut());
-            Ok(builder.body(axum::body::Body::wrap_stream(subresp.bytes_stream()))?)
+            Ok(builder.body(body)?)
         } else {
-            tracing::info!(
-                method = ?request.method(),
-                requested = url,
-                status = 404
-            );
-            return Ok(Response::builder()
-                .status(404)
-                .body(axum::body::Body::empty())?);
+            Ok(errors::respond(
+                table.error_format,
+                StatusCode::NOT_FOUND,
+                "no_matching_rule",
+                "no rule matches the requested url",
+                request_id,
+                None,
+            ))
+        }
+    }
+
+    /// Sends `subrequest` within the limits of `item`, failing early when the
+    /// target is refused and giving up once the target has been drained.
+    /// Targets on a Unix `socket` are local, so the target checks don't
+    /// apply to them. The phases of the exchange are marked on `timeline`.
+    async fn forward(
+        client: &upstream::Client<connector::Connector>,
+        item: &ProxyItem,
+        subrequest: reqwest::Request,
+        socket: Option<&std::path::Path>,
+        timeline: &Arc<timing::Timeline>,
+        cutoff: &mut tokio::sync::watch::Receiver<bool>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let remote = socket.is_none();
+        if remote && item.block_internal_targets && ssrf::is_internal_literal(subrequest.url()) {
+            let host = subrequest.url().host_str().unwrap_or_default().to_string();
+            return Err(ssrf::InternalTarget(host).into());
+        }
+        if remote && item.force_https.is_some() && subrequest.url().scheme() != "https" {
+            return Err(scheme::InsecureTarget(subrequest.url().to_string()).into());
+        }
+        let execute = timeline.run(async {
+            match socket {
+                Some(socket) => item.unix_client.send(socket, subrequest).await,
+                None => client.execute(subrequest).await,
+            }
+        });
+        let first_byte = async {
+            match item.first_byte_timeout.or(item.read_timeout) {
+                Some(timeout) => tokio::time::timeout(timeout, execute)
+                    .await
+                    .map_err(|_| errors::FirstByteTimeout(timeout))?,
+                None => execute.await,
+            }
+        };
+        let mut subresp = tokio::select! {
+            subresp = first_byte => subresp?,
+            _ = cutoff.wait_for(|retired| *retired) => anyhow::bail!("target drained"),
+        };
+        if let Some(limit) = item.max_response_header_size {
+            let size = subresp
+                .headers()
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len() + 4)
+                .sum::<usize>();
+            if size > limit {
+                return Err(errors::HeadersTooLarge(size).into());
+            }
+        }
+        if let Some(limit) = item.max_response_size {
+            if subresp
+                .content_length()
+                .is_some_and(|size| size > limit as u64)
+            {
+                return Err(errors::ResponseTooLarge(limit).into());
+            }
+        }
+        item.rewrite_response_headers(subresp.headers_mut())?;
+        item.cookies.apply(subresp.headers_mut())?;
+        if let Some(content_type) = &item.response_content_type {
+            content_type.apply(subresp.headers_mut());
         }
+        Ok(subresp)
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+fn main() -> anyhow::Result<()> {
     let cli_args: CliArgs = argh::from_env();
 
     if cli_args.version {
         println!("alpha");
         return Ok(())
     }
+    match &cli_args.command {
+        Some(Command::Lint(command)) => return lint::run(command),
+        Some(Command::Repl(command)) => return repl::run(command),
+        None => {}
+    }
+    if cli_args.selftest {
+        return tokio::runtime::Runtime::new()?.block_on(selftest::run(
+            cli_args.selftest_requests,
+            cli_args.selftest_concurrency,
+        ));
+    }
 
-    let config: Config = serde_yaml::from_reader(std::fs::File::open(cli_args.config.unwrap())?)?;
+    let config = match (&cli_args.config, cli_args.proxy.is_empty()) {
+        (Some(path), true) => load_config(path)?,
+        (None, false) => inline::parse(&cli_args.proxy)?,
+        (Some(_), false) => anyhow::bail!("`--config` and inline rules are exclusive"),
+        (None, true) => anyhow::bail!("either `--config` or inline rules are required"),
+    };
+    labels::init(config.environment.as_deref(), config.instance.as_deref());
+    let config_path = cli_args.config.clone();
+    let table = parse_config(&config)?;
+    let worker = supervisor::Worker::from_env()?;
+    // the supervisor has probed already
+    if let (Some(mode), None) = (cli_args.probe_targets, &worker) {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(probe::probe(&table, mode))?;
+    }
+    // Certificates are loaded while the key files are still readable, before
+    // privileges are dropped.
+    let tls_cert = cli_args.tls_cert.as_ref().or(config.tls_cert.as_ref());
+    let tls_key = cli_args.tls_key.as_ref().or(config.tls_key.as_ref());
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+        (None, None) => None,
+        _ => anyhow::bail!("`tls_cert` and `tls_key` must be given together"),
+    };
+    let quic_config = match (cli_args.h3_port, tls_cert.zip(tls_key)) {
+        (Some(_), _) if cli_args.workers.is_some() => {
+            anyhow::bail!("`--h3-port` can't be used with `--workers`")
+        }
+        (Some(0), _) => anyhow::bail!("`--h3-port` can't be 0, clients are told the port"),
+        (Some(_), Some((cert, key))) => Some(quic::server_config(cert, key)?),
+        (Some(_), None) => anyhow::bail!("`--h3-port` needs a certificate and key"),
+        (None, _) => None,
+    };
 
-    let state = AppState {
-        proxy_items: parse_config(&config)?,
+    // Bind before daemonizing so errors are still reported to the terminal.
+    let bind = |host: &str, port: u16| -> anyhow::Result<std::net::TcpListener> {
+        let address = format!("{host}:{port}");
+        let tcp = match cli_args.workers {
+            Some(_) => supervisor::bind_shared(&address),
+            None => std::net::TcpListener::bind(&address).map_err(Into::into),
+        }
+        .map_err(|err| anyhow::anyhow!("{address}: {err}"))?;
+        tcp.set_nonblocking(true)?;
+        Ok(tcp)
     };
-    let app = Router::new()
+    let addresses = listen_addresses(&cli_args, &config)?;
+    let ephemeral = addresses.iter().any(|(_, port)| *port == 0) || cli_args.tls_port == Some(0);
+    if ephemeral && cli_args.workers.is_some() {
+        // every worker would bind a port of its own
+        anyhow::bail!("port 0 can't be used with `--workers`");
+    }
+    // the TLS and HTTP/3 ports are bound once per host, whatever its ports
+    let mut hosts: Vec<&str> = Vec::new();
+    for (host, _) in addresses.iter() {
+        if !hosts.contains(&host.as_str()) {
+            hosts.push(host);
+        }
+    }
+    let mut listeners = Vec::new();
+    match (&tls, cli_args.tls_port) {
+        (Some(tls), Some(tls_port)) => {
+            for (host, port) in addresses.iter() {
+                listeners.push((bind(host, *port)?, None));
+            }
+            for host in &hosts {
+                listeners.push((bind(host, tls_port)?, Some(tls.clone())));
+            }
+        }
+        (None, Some(_)) => anyhow::bail!("`--tls-port` needs a certificate and key"),
+        (tls, None) => {
+            for (host, port) in addresses.iter() {
+                listeners.push((bind(host, *port)?, tls.clone()));
+            }
+        }
+    }
+    let urls = listeners
+        .iter()
+        .map(|(tcp, tls)| {
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            Ok(format!("{scheme}://{}", tcp.local_addr()?))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if ephemeral {
+        for url in &urls {
+            println!("listening on {url}");
+        }
+    }
+    let quic = match (quic_config, cli_args.h3_port) {
+        (Some(config), Some(port)) => {
+            let sockets = hosts
+                .iter()
+                .map(|host| {
+                    let address = format!("{host}:{port}");
+                    std::net::UdpSocket::bind(&address)
+                        .map_err(|err| anyhow::anyhow!("{address}: {err}"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some((config, sockets))
+        }
+        _ => None,
+    };
+    let admin = match (cli_args.admin_listen, cli_args.workers) {
+        (Some(_), Some(_)) => anyhow::bail!("`--admin-listen` can't be used with `--workers`"),
+        (Some(address), None) => {
+            let tcp = std::net::TcpListener::bind(address)?;
+            tcp.set_nonblocking(true)?;
+            Some(tcp)
+        }
+        (None, _) => None,
+    };
+
+    if worker.is_none() {
+        if cli_args.daemon {
+            daemon::daemonize()?;
+        }
+        if let Some(workers) = cli_args.workers {
+            // The supervisor only bound to check the ports; workers bind
+            // their own and drop privileges themselves.
+            drop(listeners);
+            let _pid_file = cli_args
+                .pid_file
+                .as_deref()
+                .map(daemon::PidFile::create)
+                .transpose()?;
+            let _port_file = cli_args
+                .port_file
+                .as_deref()
+                .map(|path| daemon::PortFile::create(path, &urls))
+                .transpose()?;
+            return tokio::runtime::Runtime::new()?.block_on(async {
+                let stats = Arc::new(report::Stats::default());
+                let _log_guards = logging::init(&log_options(&cli_args), stats.clone())?;
+                supervisor::run(
+                    workers,
+                    stats,
+                    cli_args.shutdown_webhook.as_deref(),
+                    config_path.as_deref(),
+                )
+                .await
+            });
+        }
+    }
+    let _pid_file = match worker {
+        Some(_) => None,
+        None => cli_args
+            .pid_file
+            .as_deref()
+            .map(daemon::PidFile::create)
+            .transpose()?,
+    };
+    let _port_file = match worker {
+        Some(_) => None,
+        None => cli_args
+            .port_file
+            .as_deref()
+            .map(|path| daemon::PortFile::create(path, &urls))
+            .transpose()?,
+    };
+    if cli_args.user.is_some() || cli_args.group.is_some() {
+        daemon::drop_privileges(cli_args.user.as_deref(), cli_args.group.as_deref())?;
+    }
+
+    tokio::runtime::Runtime::new()?.block_on(run(
+        cli_args,
+        config_path,
+        table,
+        listeners,
+        quic,
+        admin,
+        worker,
+    ))
+}
+
+/// The hosts and ports to listen on, from `--host` and `--port` or else the
+/// `listen` list of the config. IPv6 hosts are bracketed.
+fn listen_addresses(cli_args: &CliArgs, config: &Config) -> anyhow::Result<Vec<(String, u16)>> {
+    if cli_args.host.is_empty() && cli_args.port.is_empty() && !config.listen.is_empty() {
+        return config
+            .listen
+            .iter()
+            .map(|address| {
+                address
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("`listen` address `{address}` isn't `host:port`")
+                    })
+            })
+            .collect();
+    }
+    let hosts: Vec<String> = match cli_args.host.as_slice() {
+        [] => vec!["127.0.0.1".to_string()],
+        hosts => hosts
+            .iter()
+            .map(|host| match host.parse::<std::net::Ipv6Addr>() {
+                Ok(_) => format!("[{host}]"),
+                Err(_) => host.clone(),
+            })
+            .collect(),
+    };
+    let ports = match cli_args.port.as_slice() {
+        [] => vec![3333],
+        ports => ports.to_vec(),
+    };
+    Ok(match (hosts.as_slice(), ports.as_slice()) {
+        (hosts, ports) if hosts.len() == ports.len() => {
+            hosts.iter().cloned().zip(ports.iter().copied()).collect()
+        }
+        ([host], ports) => ports.iter().map(|port| (host.clone(), *port)).collect(),
+        (hosts, [port]) => hosts.iter().map(|host| (host.clone(), *port)).collect(),
+        _ => {
+            anyhow::bail!("`--host` and `--port` must be given equally often, or one of them once")
+        }
+    })
+}
+
+fn log_options(cli_args: &CliArgs) -> logging::LogOptions {
+    logging::LogOptions {
+        access_log: cli_args.access_log.clone(),
+        access_log_format: cli_args.access_log_format.clone(),
+        error_log: cli_args.error_log.clone(),
+        error_log_level: cli_args.error_log_level.clone(),
+        error_log_format: cli_args.error_log_format,
+    }
+}
+
+async fn run(
+    cli_args: CliArgs,
+    config_path: Option<String>,
+    table: ProxyTable,
+    listeners: Vec<(std::net::TcpListener, Option<tokio_rustls::TlsAcceptor>)>,
+    quic: Option<(quinn::ServerConfig, Vec<std::net::UdpSocket>)>,
+    admin: Option<std::net::TcpListener>,
+    worker: Option<supervisor::Worker>,
+) -> anyhow::Result<()> {
+    let stats = Arc::new(report::Stats::default());
+    let _log_guards = logging::init(&log_options(&cli_args), stats.clone())?;
+    let worker = worker.map(Arc::new);
+    let snapshots = worker
+        .as_ref()
+        .map(|worker| worker.spawn_snapshots(stats.clone()));
+
+    let table = Arc::new(table);
+    let state = Arc::new(AppState {
+        health_checks: Mutex::new(health::spawn(&table)),
+        table: RwLock::new(table),
+    });
+    // inline rules have no file to reload from
+    if let Some(config_path) = config_path {
+        reload::spawn(state.clone(), config_path)?;
+    }
+    if let Some(admin) = admin {
+        tracing::info!(address = %admin.local_addr()?, "admin api");
+        let server = axum::Server::from_tcp(admin)?
+            .serve(admin::router(state.clone(), stats.clone()).into_make_service());
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                tracing::error!(error = ?err, "admin api failed");
+            }
+        });
+    }
+    let mut app = Router::new()
         .route("/*_", any(handle_request))
-        .with_state(Arc::new(state));
-    tracing::info!(host = cli_args.host, port = cli_args.port, "listen");
-    axum::Server::bind(
-        &format!("{}:{}", cli_args.host, cli_args.port)
-            .parse()
-            .unwrap(),
-    )
-    .serve(app.into_make_service())
-    .await
-    .unwrap();
+        .with_state(state.clone());
+    if let Some(port) = cli_args.h3_port {
+        let alt_svc = quic::alt_svc(port);
+        app = app.layer(axum::middleware::map_response(
+            move |mut response: Response| {
+                let alt_svc = alt_svc.clone();
+                async move {
+                    response.headers_mut().insert(header::ALT_SVC, alt_svc);
+                    response
+                }
+            },
+        ));
+    }
+    let limits = listener::ConnectionLimits {
+        keepalive_timeout: Some(
+            cli_args
+                .keepalive_timeout
+                .map_or(Duration::from_secs(75), Into::into),
+        ),
+        max_requests: cli_args.max_requests_per_connection,
+        h2_ping_interval: cli_args.h2_ping_interval.map(Into::into),
+        h2_ping_timeout: cli_args
+            .h2_ping_timeout
+            .map_or(Duration::from_secs(20), Into::into),
+        in_flight: cli_args.max_in_flight_requests.map(|max| {
+            Arc::new(listener::InFlightLimit::new(
+                max,
+                cli_args
+                    .in_flight_queue_timeout
+                    .map_or(Duration::ZERO, Into::into),
+                state.clone(),
+            ))
+        }),
+    };
+    let shutdown = Arc::new(listener::Shutdown::default());
+    let mut servers = Vec::new();
+    for (tcp, tls) in listeners {
+        let address = tcp.local_addr()?;
+        tracing::info!(
+            host = %address.ip(),
+            port = address.port(),
+            tls = tls.is_some(),
+            worker = worker.as_ref().map(|worker| worker.index),
+            "listen"
+        );
+        let tcp = tokio::net::TcpListener::from_std(tcp)?;
+        servers.push(listener::serve(
+            tcp,
+            app.clone(),
+            limits.clone(),
+            tls,
+            shutdown.clone(),
+        ));
+    }
+    let mut quic_servers = Vec::new();
+    for (config, socket) in quic.into_iter().flat_map(|(config, sockets)| {
+        sockets
+            .into_iter()
+            .map(move |socket| (config.clone(), socket))
+    }) {
+        let endpoint = quic::endpoint(socket, config)?;
+        let address = endpoint.local_addr()?;
+        tracing::info!(
+            host = %address.ip(),
+            port = address.port(),
+            protocol = "http/3",
+            "listen"
+        );
+        quic_servers.push(quic::serve(
+            endpoint,
+            app.clone(),
+            limits.in_flight.clone(),
+            shutdown.clone(),
+        ));
+    }
+    // the listeners close when the servers are dropped here
+    tokio::select! {
+        _ = futures_util::future::join(
+            futures_util::future::join_all(servers),
+            futures_util::future::join_all(quic_servers),
+        ) => {}
+        result = stop_signal() => result?,
+    }
+
+    let open = shutdown.begin();
+    tracing::info!(connections = open, "shutting down");
+    let aborted = shutdown
+        .drain(
+            cli_args
+                .shutdown_timeout
+                .map_or(Duration::from_secs(30), Into::into),
+        )
+        .await;
+    let report = report::Report::new(&stats, open.saturating_sub(aborted), aborted);
+    match worker {
+        // the supervisor publishes the report of all workers
+        Some(worker) => {
+            snapshots
+                .into_iter()
+                .for_each(|snapshots| snapshots.abort());
+            worker.send(report);
+        }
+        None => report.publish(cli_args.shutdown_webhook.as_deref()).await,
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+async fn stop_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
@@ -0,0 +1,102 @@
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A soft limit on memory held for a purpose, such as buffered response
+/// bodies. Usage is tracked even without a limit so it can be reported.
+pub struct Budget {
+    name: &'static str,
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// set while reservations are refused, to log only the transitions
+    exhausted: AtomicBool,
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    used: usize,
+    limit: Option<usize>,
+}
+
+impl Budget {
+    pub fn new(name: &'static str, limit: Option<usize>) -> Arc<Self> {
+        Arc::new(Budget {
+            name,
+            limit,
+            used: AtomicUsize::new(0),
+            exhausted: AtomicBool::new(false),
+        })
+    }
+
+    /// An empty reservation to [`Reservation::grow`].
+    pub fn reservation(self: &Arc<Self>) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    /// Takes `bytes` from the budget, or `None` if that would exceed the
+    /// limit.
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize) -> Option<Reservation> {
+        let mut reservation = self.reservation();
+        reservation.grow(bytes).then_some(reservation)
+    }
+
+    /// Whether `bytes` more would stay within the limit.
+    pub fn fits(&self, bytes: usize) -> bool {
+        let used = self.used.load(Ordering::Relaxed);
+        self.limit.is_none_or(|limit| used + bytes <= limit)
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            used: self.used.load(Ordering::Relaxed),
+            limit: self.limit,
+        }
+    }
+}
+
+/// Memory taken from a [`Budget`], given back when dropped.
+pub struct Reservation {
+    budget: Arc<Budget>,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Takes `bytes` more, unless that would exceed the limit.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        let budget = &self.budget;
+        let reserved = budget
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let used = used + bytes;
+                budget
+                    .limit
+                    .is_none_or(|limit| used <= limit)
+                    .then_some(used)
+            })
+            .is_ok();
+        if reserved {
+            self.bytes += bytes;
+            if budget.exhausted.swap(false, Ordering::Relaxed) {
+                tracing::info!(budget = budget.name, "memory back under limit");
+            }
+        } else if !budget.exhausted.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                budget = budget.name,
+                limit = budget.limit,
+                "memory limit reached"
+            );
+        }
+        reserved
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,55 @@
+use axum::{
+    body::Body,
+    http::{header, Method, Response, StatusCode},
+};
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Fixed documents like `/robots.txt` answered by the proxy itself, keyed by
+/// host (`*` for any host) and then by path.
+pub struct Overrides(HashMap<String, HashMap<String, (&'static str, Bytes)>>);
+
+impl Overrides {
+    pub fn new(config: &HashMap<String, HashMap<String, String>>) -> anyhow::Result<Self> {
+        let mut overrides = HashMap::new();
+        for (host, paths) in config.iter() {
+            let mut documents = HashMap::new();
+            for (path, content) in paths.iter() {
+                if !path.starts_with('/') {
+                    anyhow::bail!("override `{path}` for `{host}` must start with `/`");
+                }
+                let content_type = if path.ends_with(".json") {
+                    "application/json"
+                } else {
+                    "text/plain; charset=utf-8"
+                };
+                documents.insert(path.clone(), (content_type, Bytes::from(content.clone())));
+            }
+            overrides.insert(host.to_lowercase(), documents);
+        }
+        Ok(Overrides(overrides))
+    }
+
+    /// The document for a GET or HEAD request of `path` on `host`, a host
+    /// specific one taking precedence over one for any host.
+    pub fn respond(&self, method: &Method, host: &str, path: &str) -> Option<Response<Body>> {
+        if method != Method::GET && method != Method::HEAD {
+            return None;
+        }
+        let host = crate::conditions::host_without_port(host).to_lowercase();
+        let (content_type, content) = [host.as_str(), "*"]
+            .iter()
+            .find_map(|host| self.0.get(*host)?.get(path))?;
+        let body = if method == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(content.clone())
+        };
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, *content_type)
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(body)
+            .ok()
+    }
+}
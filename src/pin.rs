@@ -0,0 +1,190 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Once, Weak,
+    },
+    time::Duration,
+};
+
+use crate::ssrf::PublicOnlyResolver;
+
+#[derive(Serialize, Deserialize)]
+pub struct PinnedHostConfig {
+    addresses: Vec<IpAddr>,
+    /// Port probed with a TCP connect to decide whether an address is
+    /// healthy. Addresses are not health checked without it.
+    #[serde(default)]
+    health_check_port: Option<u16>,
+    #[serde(default = "default_interval", with = "humantime_serde")]
+    health_check_interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+struct PinnedHost {
+    name: String,
+    addresses: Vec<(IpAddr, AtomicBool)>,
+    next: AtomicUsize,
+    health_check: Option<(u16, Duration)>,
+    started: Once,
+}
+
+impl PinnedHost {
+    /// Healthy addresses, rotated so consecutive connections spread over
+    /// them. When all are down every address is returned, as a stale health
+    /// state is better than failing outright.
+    fn addrs(&self) -> Vec<SocketAddr> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.addresses.len();
+        let rotated = (0..len).map(|offset| &self.addresses[(start + offset) % len]);
+        let healthy: Vec<SocketAddr> = rotated
+            .clone()
+            .filter(|(_, up)| up.load(Ordering::Relaxed))
+            .map(|(ip, _)| SocketAddr::new(*ip, 0))
+            .collect();
+        if healthy.is_empty() {
+            rotated.map(|(ip, _)| SocketAddr::new(*ip, 0)).collect()
+        } else {
+            healthy
+        }
+    }
+
+    /// Starts the health checker on first use, from within the runtime. It
+    /// stops once the host config has been dropped by a reload.
+    fn start(self: &Arc<Self>) {
+        let Some((port, interval)) = self.health_check else {
+            return;
+        };
+        self.started.call_once(|| {
+            let host = Arc::downgrade(self);
+            tokio::spawn(check(host, port, interval));
+        });
+    }
+}
+
+async fn check(host: Weak<PinnedHost>, port: u16, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let Some(host) = host.upgrade() else {
+            return;
+        };
+        for (ip, up) in host.addresses.iter() {
+            let probe = tokio::net::TcpStream::connect(SocketAddr::new(*ip, port));
+            let healthy = matches!(tokio::time::timeout(interval, probe).await, Ok(Ok(_)));
+            if up.swap(healthy, Ordering::Relaxed) != healthy {
+                tracing::warn!(host = host.name, address = %ip, healthy, "pinned address");
+            }
+        }
+    }
+}
+
+/// Resolves pinned hosts to their configured addresses and everything else
+/// through the system resolver, or the public-only one when internal targets
+/// are blocked. Pinned addresses are an explicit operator choice and are not
+/// filtered.
+pub struct PinnedResolver {
+    hosts: HashMap<String, Arc<PinnedHost>>,
+    block_internal: bool,
+}
+
+impl PinnedResolver {
+    pub fn new(config: &HashMap<String, PinnedHostConfig>, block_internal: bool) -> Self {
+        let hosts = config
+            .iter()
+            .map(|(name, host)| {
+                let pinned = PinnedHost {
+                    name: name.clone(),
+                    addresses: host
+                        .addresses
+                        .iter()
+                        .map(|ip| (*ip, AtomicBool::new(true)))
+                        .collect(),
+                    next: AtomicUsize::new(0),
+                    health_check: host
+                        .health_check_port
+                        .map(|port| (port, host.health_check_interval)),
+                    started: Once::new(),
+                };
+                (name.to_lowercase(), Arc::new(pinned))
+            })
+            .collect();
+        PinnedResolver {
+            hosts,
+            block_internal,
+        }
+    }
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        match self.hosts.get(name.as_str()) {
+            Some(host) => {
+                host.start();
+                let addrs = host.addrs();
+                Box::pin(async move { Ok(Box::new(addrs.into_iter()) as Addrs) })
+            }
+            None if self.block_internal => PublicOnlyResolver.resolve(name),
+            None => Box::pin(async move {
+                let addrs: Vec<SocketAddr> =
+                    tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+                Ok(Box::new(addrs.into_iter()) as Addrs)
+            }),
+        }
+    }
+}
+
+/// Resolves the TLS server name of a rule to the target's host, so requests
+/// addressed to that name still reach the target. Other names, and the host
+/// itself, resolve like they would without the override.
+pub struct ServerNameResolver {
+    name: String,
+    host: String,
+    pinned: Option<Arc<PinnedResolver>>,
+    block_internal: bool,
+}
+
+impl ServerNameResolver {
+    pub fn new(
+        name: &str,
+        host: &str,
+        pinned: Option<Arc<PinnedResolver>>,
+        block_internal: bool,
+    ) -> Self {
+        ServerNameResolver {
+            name: name.to_lowercase(),
+            host: host.trim_matches(['[', ']']).to_string(),
+            pinned,
+            block_internal,
+        }
+    }
+}
+
+impl Resolve for ServerNameResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let name = if name.as_str().eq_ignore_ascii_case(&self.name) {
+            match self.host.parse::<Name>() {
+                Ok(host) => host,
+                Err(err) => return Box::pin(async move { Err(err.into()) }),
+            }
+        } else {
+            name
+        };
+        match &self.pinned {
+            Some(pinned) => pinned.resolve(name),
+            None if self.block_internal => PublicOnlyResolver.resolve(name),
+            None => Box::pin(async move {
+                let addrs: Vec<SocketAddr> =
+                    tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+                Ok(Box::new(addrs.into_iter()) as Addrs)
+            }),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use std::{str::FromStr, time::Duration};
+use tokio::{net::TcpStream, task::JoinSet};
+
+use crate::ProxyTable;
+
+#[derive(Clone, Copy)]
+pub enum ProbeMode {
+    Warn,
+    Fail,
+}
+
+impl FromStr for ProbeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(ProbeMode::Warn),
+            "fail" => Ok(ProbeMode::Fail),
+            _ => Err(format!(
+                "unknown probe mode `{s}`, expected `warn` or `fail`"
+            )),
+        }
+    }
+}
+
+/// The host and port a target template always dials, if they don't depend on
+/// capture groups.
+pub fn static_authority(template: &str) -> Option<(String, u16)> {
+    let literal = template.split('$').next()?;
+    let (scheme, rest) = literal.split_once("://")?;
+    // the authority must be complete before the first substitution: followed
+    // by a path, or ending in an explicit port as in `http://host:8080$1`
+    let complete = rest.contains(['/', '?', '#'])
+        || literal.len() == template.len()
+        || rest
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()));
+    if !complete {
+        return None;
+    }
+    let url = reqwest::Url::parse(&format!("{scheme}://{rest}")).ok()?;
+    Some((url.host_str()?.to_string(), url.port_or_known_default()?))
+}
+
+/// Connects to every statically known target in parallel, reporting those that
+/// can't be reached. Targets built from capture groups are skipped.
+pub async fn probe(table: &ProxyTable, mode: ProbeMode) -> anyhow::Result<()> {
+    let mut probes = JoinSet::new();
+    for item in table.items.iter() {
+        for target in item.targets.iter() {
+            let Some((host, port)) = static_authority(&target.template) else {
+                continue;
+            };
+            let rule = item.name.clone();
+            probes.spawn(async move {
+                let connect = TcpStream::connect((host.as_str(), port));
+                let result = match tokio::time::timeout(Duration::from_secs(3), connect).await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(err)) => Err(err.to_string()),
+                    Err(_) => Err("timed out".to_string()),
+                };
+                result.map_err(|err| format!("{rule}: {host}:{port} is unreachable: {err}"))
+            });
+        }
+    }
+
+    let mut failures = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        if let Err(failure) = result? {
+            eprintln!("warning: {failure}");
+            failures.push(failure);
+        }
+    }
+    match mode {
+        ProbeMode::Fail if !failures.is_empty() => {
+            anyhow::bail!("{} target(s) failed the startup probe", failures.len())
+        }
+        _ => Ok(()),
+    }
+}
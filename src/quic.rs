@@ -0,0 +1,157 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::ConnectInfo,
+    http::{HeaderValue, Request, Response},
+    Router,
+};
+use bytes::{Buf, Bytes};
+use futures_util::stream;
+use h3::{error::ErrorLevel, server::RequestStream};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::task::JoinSet;
+use tower::ServiceExt;
+
+use crate::{downgrade, listener, tls};
+
+/// The certificate and key of the HTTPS listener, for QUIC connections
+/// negotiating HTTP/3.
+pub fn server_config(cert_path: &str, key_path: &str) -> anyhow::Result<quinn::ServerConfig> {
+    let mut config = tls::server_config(cert_path, key_path)?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(config)))
+}
+
+/// The `Alt-Svc` value telling clients HTTP/3 is served on `port`.
+pub fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400")).unwrap()
+}
+
+/// A QUIC endpoint on the bound `socket`.
+pub fn endpoint(
+    socket: std::net::UdpSocket,
+    config: quinn::ServerConfig,
+) -> std::io::Result<quinn::Endpoint> {
+    quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+}
+
+/// Accepts QUIC connections on `endpoint` and serves their HTTP/3 requests
+/// with `app`, within the same in-flight limit as the other listeners.
+pub async fn serve(
+    endpoint: quinn::Endpoint,
+    app: Router,
+    in_flight: Option<Arc<listener::InFlightLimit>>,
+    shutdown: Arc<listener::Shutdown>,
+) {
+    while let Some(connecting) = endpoint.accept().await {
+        let peer = connecting.remote_address();
+        let app = app.clone();
+        let in_flight = in_flight.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let result = match connecting.await {
+                Ok(connection) => drive(connection, peer, app, in_flight, shutdown).await,
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                tracing::debug!(error = ?err, peer = ?peer, "quic connection");
+            }
+        });
+    }
+}
+
+/// Serves the requests of one connection until it closes, or until the
+/// process stops and its requests are done.
+async fn drive(
+    connection: quinn::Connection,
+    peer: SocketAddr,
+    app: Router,
+    in_flight: Option<Arc<listener::InFlightLimit>>,
+    shutdown: Arc<listener::Shutdown>,
+) -> anyhow::Result<()> {
+    let mut stopping = shutdown.started();
+    let _open = listener::ConnectionGuard::new(shutdown);
+    let mut connection =
+        h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+    let mut requests = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = connection.accept() => match accepted {
+                Ok(Some((request, stream))) => {
+                    let app = app.clone();
+                    let in_flight = in_flight.clone();
+                    requests.spawn(async move {
+                        if let Err(err) = respond(request, stream, peer, app, in_flight).await {
+                            tracing::debug!(error = ?err, peer = ?peer, "http/3 request");
+                        }
+                    });
+                }
+                Ok(None) => break,
+                Err(err) => match err.get_error_level() {
+                    ErrorLevel::ConnectionError => Err(err)?,
+                    ErrorLevel::StreamError => continue,
+                },
+            },
+            // connections are only accepted before the shutdown starts
+            _ = stopping.changed() => {
+                connection.shutdown(0).await?;
+                break;
+            }
+        }
+    }
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn respond(
+    request: Request<()>,
+    stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    peer: SocketAddr,
+    app: Router,
+    in_flight: Option<Arc<listener::InFlightLimit>>,
+) -> anyhow::Result<()> {
+    let (mut send, receive) = stream.split();
+    let body = stream::unfold(receive, |mut receive| async move {
+        let chunk = match receive.recv_data().await {
+            Ok(Some(mut data)) => Ok(data.copy_to_bytes(data.remaining())),
+            Ok(None) => return None,
+            Err(err) => Err(err),
+        };
+        Some((chunk, receive))
+    });
+    let (parts, ()) = request.into_parts();
+    let mut request = Request::from_parts(parts, Body::wrap_stream(body));
+    request.extensions_mut().insert(ConnectInfo(peer));
+    request.extensions_mut().insert(listener::Tls);
+
+    // held until the response body is sent, as on the other listeners
+    let slot = match &in_flight {
+        Some(limit) => match limit.acquire().await {
+            Some(slot) => Some(slot),
+            None => {
+                let (parts, body) = limit.overloaded(&request, peer).into_parts();
+                send.send_response(Response::from_parts(parts, ())).await?;
+                send.send_data(hyper::body::to_bytes(body).await?).await?;
+                return Ok(send.finish().await?);
+            }
+        },
+        None => None,
+    };
+    let response = app.oneshot(request).await?;
+    let (mut parts, mut body) = response.into_parts();
+    downgrade::strip_hop_headers(&mut parts.headers);
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    if let Some(trailers) = body.trailers().await? {
+        send.send_trailers(trailers).await?;
+    }
+    send.finish().await?;
+    drop(slot);
+    Ok(())
+}
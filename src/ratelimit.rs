@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Clients whose buckets are remembered per rule; the least recently seen
+/// are forgotten first, which only ever grants them a full burst again.
+const TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    /// requests a client may send at once after being idle (default: the
+    /// rate, at least 1)
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client address.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<lru::LruCache<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> anyhow::Result<Self> {
+        let rate = config.requests_per_second;
+        if !(rate > 0.0 && rate.is_finite()) {
+            anyhow::bail!("`rate_limit.requests_per_second` must be positive");
+        }
+        let burst = match config.burst {
+            Some(0) => anyhow::bail!("`rate_limit.burst` must be at least 1"),
+            Some(burst) => burst as f64,
+            None => rate.ceil(),
+        };
+        Ok(RateLimiter {
+            rate,
+            burst,
+            buckets: Mutex::new(lru::LruCache::new(
+                NonZeroUsize::new(TRACKED_CLIENTS).unwrap(),
+            )),
+        })
+    }
+
+    /// Takes a token for a request from `client`, or returns the time until
+    /// one is available.
+    pub fn acquire(&self, client: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(client, || Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{connector::Connector, upstream};
+
+type Build = Box<dyn Fn() -> upstream::Client<Connector> + Send + Sync>;
+
+/// An upstream client whose connection pool is replaced once it is older than
+/// `max_age` or has sent `max_requests`, so no upstream connection outlives
+/// those limits. Requests still running on the old pool finish on it, and its
+/// connections close once they are done.
+pub struct RecyclingClient {
+    build: Build,
+    max_age: Option<Duration>,
+    max_requests: Option<usize>,
+    current: Mutex<Generation>,
+}
+
+struct Generation {
+    client: upstream::Client<Connector>,
+    created: Instant,
+    requests: usize,
+}
+
+impl RecyclingClient {
+    pub fn new(build: Build, max_age: Option<Duration>, max_requests: Option<usize>) -> Self {
+        let client = build();
+        RecyclingClient {
+            build,
+            max_age,
+            max_requests,
+            current: Mutex::new(Generation {
+                client,
+                created: Instant::now(),
+                requests: 0,
+            }),
+        }
+    }
+
+    /// The client to send the next request with.
+    pub fn get(&self) -> upstream::Client<Connector> {
+        let mut current = self.current.lock().unwrap();
+        let expired = self
+            .max_age
+            .is_some_and(|age| current.created.elapsed() >= age)
+            || self.max_requests.is_some_and(|max| current.requests >= max);
+        if expired {
+            *current = Generation {
+                client: (self.build)(),
+                created: Instant::now(),
+                requests: 0,
+            };
+        }
+        current.requests += 1;
+        current.client.clone()
+    }
+}
@@ -0,0 +1,74 @@
+use serde_json::Value;
+
+pub const REDACTED: &str = "<redacted>";
+
+/// A copy of a rule's config with its secrets masked, see `redact`.
+pub fn redacted(rule: &Value) -> Value {
+    let mut rule = rule.clone();
+    redact(&mut rule);
+    rule
+}
+
+/// Masks the secrets of a rule's config wherever it is shown or logged:
+/// login passwords, the static values of request headers, which often carry
+/// upstream credentials, and the error budget webhook, whose URL is the only
+/// credential chat webhooks take.
+pub fn redact(rule: &mut Value) {
+    if let Some(users) = rule.pointer_mut("/login/users") {
+        *users = Value::from(REDACTED);
+    }
+    if let Some(Value::Object(headers)) = rule.get_mut("headers") {
+        for header in headers.values_mut() {
+            for field in ["set", "replace", "default_value"] {
+                if let Some(value @ Value::String(_)) = header.get_mut(field) {
+                    *value = Value::from(REDACTED);
+                }
+            }
+        }
+    }
+    if let Some(webhook @ Value::String(_)) = rule.pointer_mut("/error_budget/webhook") {
+        *webhook = Value::from(REDACTED);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn redacts_passwords_and_request_header_values() {
+        let rule = json!({
+            "match": "^/",
+            "login": { "users": { "alice": "hunter2" }, "title": "Staff" },
+            "headers": {
+                "authorization": { "set": "Bearer secret" },
+                "x-tenant": { "match": "^(.*)$", "replace": "t-$1", "default_value": "t-0" },
+                "x-forwarded-host": null,
+            },
+            "response_headers": { "server": { "set": "reproxy" } },
+            "error_budget": {
+                "max_error_rate": 0.05,
+                "webhook": "https://hooks.slack.com/services/T0/B0/secret",
+            },
+        });
+        let redacted = redacted(&rule);
+        assert_eq!(redacted["login"]["users"], REDACTED);
+        assert_eq!(redacted["login"]["title"], "Staff");
+        assert_eq!(redacted["headers"]["authorization"]["set"], REDACTED);
+        assert_eq!(redacted["headers"]["x-tenant"]["match"], "^(.*)$");
+        assert_eq!(redacted["headers"]["x-tenant"]["replace"], REDACTED);
+        assert_eq!(redacted["headers"]["x-tenant"]["default_value"], REDACTED);
+        assert_eq!(redacted["headers"]["x-forwarded-host"], Value::Null);
+        assert_eq!(redacted["response_headers"]["server"]["set"], "reproxy");
+        assert_eq!(redacted["error_budget"]["webhook"], REDACTED);
+        assert_eq!(redacted["error_budget"]["max_error_rate"], 0.05);
+    }
+
+    #[test]
+    fn leaves_rules_without_secrets_alone() {
+        let rule =
+            json!({ "match": "^/", "target": "http://127.0.0.1:3400/", "error_budget": null });
+        assert_eq!(redacted(&rule), rule);
+    }
+}
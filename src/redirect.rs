@@ -0,0 +1,83 @@
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{conditions, scheme, ssrf};
+
+/// What happens to `Authorization` and `Cookie` headers when a redirect leads
+/// to another origin.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectCredentials {
+    /// follow without the credentials; the upstream client drops them when
+    /// host or port change, so redirects changing only the scheme are
+    /// handed to the client instead
+    #[default]
+    Strip,
+    /// hand every cross-origin redirect to the client
+    Stop,
+}
+
+/// How a rule follows redirects returned by its upstream.
+#[derive(Clone)]
+pub struct RedirectRules {
+    pub max_hops: usize,
+    /// Hosts redirects may lead to, either exact names or `*.domain`
+    /// wildcards. Empty allows any host.
+    pub allowlist: Arc<Vec<String>>,
+    pub block_internal: bool,
+    pub https_only: bool,
+    pub credentials: RedirectCredentials,
+}
+
+impl RedirectRules {
+    /// Whether the redirect to `next`, after the urls in `previous`, is
+    /// followed. Redirects to hosts outside the allowlist are not followed but
+    /// handed to the client as is, so the proxy can't be steered into
+    /// internal hosts.
+    pub fn follows(&self, next: &Url, previous: &[Url]) -> anyhow::Result<bool> {
+        if previous.len() > self.max_hops {
+            anyhow::bail!("too many redirects");
+        } else if self.block_internal && ssrf::is_internal_literal(next) {
+            let host = next.host_str().unwrap_or_default().to_string();
+            return Err(ssrf::InternalTarget(host).into());
+        } else if self.https_only && next.scheme() != "https" {
+            return Err(scheme::InsecureTarget(next.to_string()).into());
+        } else if !self.allows(next.host_str().unwrap_or_default()) {
+            tracing::warn!(
+                location = next.as_str(),
+                "redirect to host outside the allowlist not followed"
+            );
+            return Ok(false);
+        } else if self.keeps_credentials_from(next, previous) {
+            tracing::warn!(
+                location = next.as_str(),
+                "cross-origin redirect not followed to keep credentials from it"
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Whether the redirect to `next` is handed to the client rather than
+    /// followed, to keep credentials from reaching another origin.
+    fn keeps_credentials_from(&self, next: &Url, previous: &[Url]) -> bool {
+        let Some(previous) = previous.last() else {
+            return false;
+        };
+        let same_authority = next.host_str() == previous.host_str()
+            && next.port_or_known_default() == previous.port_or_known_default();
+        match self.credentials {
+            RedirectCredentials::Strip => same_authority && next.scheme() != previous.scheme(),
+            RedirectCredentials::Stop => !same_authority || next.scheme() != previous.scheme(),
+        }
+    }
+
+    fn allows(&self, host: &str) -> bool {
+        self.allowlist.is_empty()
+            || self
+                .allowlist
+                .iter()
+                .any(|allowed| conditions::host_matches(allowed, host))
+    }
+}
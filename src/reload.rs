@@ -0,0 +1,161 @@
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::{drain, health, load_config, parse_config, redact, AppState, ProxyItem};
+
+/// Reloads the config file whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn spawn(state: Arc<AppState>, path: String) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match reload(&state, &path) {
+                Ok(changes) => tracing::info!(
+                    path,
+                    added = changes.added,
+                    removed = changes.removed,
+                    changed = changes.changed,
+                    "config reloaded"
+                ),
+                Err(err) => tracing::error!(path, error = ?err, "config reload failed"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_state: Arc<AppState>, _path: String) -> anyhow::Result<()> {
+    Ok(())
+}
+
+fn reload(state: &AppState, path: &str) -> anyhow::Result<Changes> {
+    let mut table = parse_config(&load_config(path)?)?;
+    let mut current = state.table.write().unwrap();
+    let changes = log_changes(&current.items, &table.items);
+    drain::carry_over(&current.items, &mut table.items);
+    *current = Arc::new(table);
+    *state.health_checks.lock().unwrap() = health::spawn(&current);
+    Ok(changes)
+}
+
+/// Numbers of rules a reload added, removed or changed.
+#[derive(Default)]
+struct Changes {
+    added: usize,
+    removed: usize,
+    changed: usize,
+}
+
+/// Logs every rule the reload adds or removes, and each setting that differs
+/// for the rules it keeps, with the old and new value.
+fn log_changes(old: &[ProxyItem], new: &[ProxyItem]) -> Changes {
+    let mut changes = Changes::default();
+    for old_item in old {
+        if !new.iter().any(|item| item.name == old_item.name) {
+            tracing::info!(rule = old_item.name, "rule removed");
+            changes.removed += 1;
+        }
+    }
+    for item in new {
+        let Some(old_item) = old.iter().find(|old_item| old_item.name == item.name) else {
+            let targets: Vec<_> = item.targets.iter().map(|target| &target.template).collect();
+            tracing::info!(
+                rule = item.name,
+                pattern = item.regex.as_str(),
+                targets = ?targets,
+                "rule added"
+            );
+            changes.added += 1;
+            continue;
+        };
+        let changed = changed_fields(&old_item.config, &item.config);
+        for (field, old_value, value) in &changed {
+            tracing::info!(
+                rule = item.name,
+                field,
+                old = %old_value,
+                new = %value,
+                "rule changed"
+            );
+        }
+        changes.changed += usize::from(!changed.is_empty());
+    }
+    changes
+}
+
+/// The settings that differ between two versions of a rule's config, with
+/// their old and new value redacted. A setting whose secret changed is still
+/// listed, even though both values then read the same.
+fn changed_fields(old: &Value, new: &Value) -> Vec<(String, Value, Value)> {
+    let (Value::Object(old_fields), Value::Object(fields)) = (old, new) else {
+        return Vec::new();
+    };
+    let (old_redacted, redacted) = (redact::redacted(old), redact::redacted(new));
+    fields
+        .iter()
+        .filter(|(field, value)| old_fields.get(*field).unwrap_or(&Value::Null) != *value)
+        .map(|(field, _)| {
+            (
+                field.clone(),
+                old_redacted.get(field).cloned().unwrap_or(Value::Null),
+                redacted[field].clone(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn logs_changed_passwords_redacted() {
+        let old = json!({ "match": "^/", "login": { "users": { "alice": "hunter2" } } });
+        let new = json!({ "match": "^/", "login": { "users": { "alice": "hunter3" } } });
+        let changed = changed_fields(&old, &new);
+        assert_eq!(changed.len(), 1);
+        let (field, old_value, value) = &changed[0];
+        assert_eq!(field, "login");
+        assert_eq!(old_value["users"], redact::REDACTED);
+        assert_eq!(value["users"], redact::REDACTED);
+        assert!(!format!("{old_value} {value}").contains("hunter"));
+    }
+
+    #[test]
+    fn logs_other_settings_as_they_are() {
+        let old = json!({ "match": "^/", "headers": { "authorization": { "set": "Bearer a" } } });
+        let new =
+            json!({ "match": "^/api/", "headers": { "authorization": { "set": "Bearer a" } } });
+        let changed = changed_fields(&old, &new);
+        assert_eq!(
+            changed,
+            [("match".to_string(), json!("^/"), json!("^/api/"))]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_header_actions_by_rule() {
+        let error = |headers: &str| {
+            let yaml = format!(
+                "api:\n  match: \"^(.*)\"\n  target: \"http://127.0.0.1:3400/$1\"\n  headers:\n{headers}"
+            );
+            let config = serde_yaml::from_str(&yaml).unwrap();
+            parse_config(&config).err().map(|err| err.to_string())
+        };
+        let invalid_value = error("    x-token:\n      set: \"a\\nb\"\n").unwrap();
+        assert!(
+            invalid_value.starts_with("api: header `x-token`"),
+            "{invalid_value}"
+        );
+        let invalid_name = error("    \"x token\":\n      set: a\n").unwrap();
+        assert!(
+            invalid_name.starts_with("api: header `x token`"),
+            "{invalid_name}"
+        );
+        assert_eq!(error("    X-Token:\n      set: a\n"), None);
+    }
+}
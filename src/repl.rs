@@ -0,0 +1,176 @@
+use argh::FromArgs;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use std::{
+    io::{BufRead, IsTerminal, Write},
+    net::{IpAddr, Ipv4Addr},
+};
+
+use crate::{authority, load_config, parse_config, ProxyItem, ProxyTable};
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "repl")]
+/// type urls to see which rule of a config matches them and where they go
+pub struct ReplCommand {
+    /// the configuration file to load
+    #[argh(option, short = 'c')]
+    config: String,
+}
+
+const HELP: &str = "\
+enter a url as host/path, optionally with a scheme, or a command:
+  :header NAME: VALUE   send a header with the following urls
+  :client ADDRESS       send the following urls from this client address
+  :clear                forget the headers
+  :reload               reload the config file
+  :quit                 leave";
+
+/// Colors the capture groups cycle through.
+const COLORS: [&str; 6] = ["31", "32", "33", "34", "35", "36"];
+
+pub fn run(command: &ReplCommand) -> anyhow::Result<()> {
+    let mut table = parse_config(&load_config(&command.config)?)?;
+    let color = std::io::stdout().is_terminal();
+    let interactive = std::io::stdin().is_terminal();
+    let mut headers = HeaderMap::new();
+    let mut client = IpAddr::from(Ipv4Addr::LOCALHOST);
+    if interactive {
+        println!("{HELP}");
+    }
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if interactive {
+            print!("> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line?;
+        let line = line.trim();
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("", _) => {}
+            (":quit" | ":q", _) => break,
+            (":help", _) => println!("{HELP}"),
+            (":clear", _) => headers.clear(),
+            (":reload", _) => match load_config(&command.config).and_then(|c| parse_config(&c)) {
+                Ok(reloaded) => {
+                    table = reloaded;
+                    println!("reloaded {}", command.config);
+                }
+                Err(err) => println!("error: {err:#}"),
+            },
+            (":client", address) => match address.trim().parse() {
+                Ok(address) => client = address,
+                Err(err) => println!("error: {err}"),
+            },
+            (":header", header) => match parse_header(header) {
+                Ok((name, value)) => {
+                    headers.append(name, value);
+                }
+                Err(err) => println!("error: {err}"),
+            },
+            (other, _) if other.starts_with(':') => println!("unknown command `{other}`"),
+            _ => explain(&table, line, &headers, client, color),
+        }
+    }
+    Ok(())
+}
+
+fn parse_header(header: &str) -> anyhow::Result<(HeaderName, HeaderValue)> {
+    let Some((name, value)) = header.split_once(':') else {
+        anyhow::bail!("expected `:header NAME: VALUE`");
+    };
+    Ok((name.trim().parse()?, value.trim().parse()?))
+}
+
+/// Prints the rule `input` matches, with its capture groups, and the urls it
+/// is forwarded to.
+fn explain(table: &ProxyTable, input: &str, headers: &HeaderMap, client: IpAddr, color: bool) {
+    let url = input
+        .split_once("://")
+        .map_or(input, |(_, rest)| rest)
+        .to_string();
+    let url = if url.contains('/') { url } else { url + "/" };
+    let host = url.split('/').next().unwrap_or_default().to_lowercase();
+
+    let found = table.find(&url, &host, headers, client);
+    for item in table.items.iter() {
+        if found.is_some_and(|found| std::ptr::eq(found, item)) {
+            break;
+        }
+        if item.regex.is_match(&url) {
+            println!("  skipped {}: its conditions don't hold", item.name);
+        }
+    }
+    let Some(item) = found else {
+        println!("no rule matches {url}");
+        return;
+    };
+    println!("matched {}: {}", item.name, highlight(item, &url, color));
+    if let Some(description) = &item.description {
+        println!("  {description}");
+    }
+    if let Some(owner) = &item.owner {
+        println!("  owner: {owner}");
+    }
+    if !item.tags.is_empty() {
+        println!("  tags: {}", item.tags.join(", "));
+    }
+    if let Some(captures) = item.regex.captures(&url) {
+        for (index, group) in captures.iter().enumerate().skip(1) {
+            let name = item
+                .regex
+                .capture_names()
+                .nth(index)
+                .flatten()
+                .map_or(String::new(), |name| format!(" ({name})"));
+            let value = group.map_or("<unmatched>".to_string(), |group| {
+                paint(&format!("{:?}", group.as_str()), index, color)
+            });
+            println!("  ${index}{name} = {value}");
+        }
+    }
+    let invalid = |target_url: &str| match authority::parse(target_url) {
+        Ok(_) => String::new(),
+        Err(err) => format!(" ({err})"),
+    };
+    for target in item.targets.iter() {
+        let target_url = item.target_url(&url, target);
+        println!("  -> {target_url}{}", invalid(&target_url));
+    }
+    if let Some(fallback) = &item.fallback {
+        let target_url = item.target_url(&url, fallback);
+        println!("  -> {target_url} (fallback){}", invalid(&target_url));
+    }
+}
+
+/// `url` with each capture group in its own color, inner groups over outer
+/// ones.
+fn highlight(item: &ProxyItem, url: &str, color: bool) -> String {
+    let Some(captures) = item.regex.captures(url).filter(|_| color) else {
+        return url.to_string();
+    };
+    let mut groups = vec![0; url.len()];
+    for (index, group) in captures.iter().enumerate().skip(1) {
+        if let Some(group) = group {
+            groups[group.range()].fill(index);
+        }
+    }
+    let mut out = String::new();
+    let mut start = 0;
+    let boundaries = url.char_indices().skip(1).map(|(position, _)| position);
+    for end in boundaries.chain([url.len()]) {
+        if end == url.len() || groups[end] != groups[start] {
+            out += &paint(&url[start..end], groups[start], color);
+            start = end;
+        }
+    }
+    out
+}
+
+fn paint(text: &str, group: usize, color: bool) -> String {
+    if !color || group == 0 {
+        return text.to_string();
+    }
+    format!("\x1b[{}m{text}\x1b[0m", COLORS[(group - 1) % COLORS.len()])
+}
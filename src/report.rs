@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::{labels, logging};
+
+/// Totals gathered from the access log events over the process lifetime.
+pub struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    /// responses with a 5xx status by the rule that matched
+    errors: Mutex<BTreeMap<String, u64>>,
+    /// requests by the rule that matched
+    matched: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: Default::default(),
+            matched: Default::default(),
+        }
+    }
+}
+
+impl Stats {
+    /// Requests and 5xx responses of rule `rule` so far.
+    pub fn rule_counts(&self, rule: &str) -> (u64, u64) {
+        let count = |counts: &Mutex<BTreeMap<String, u64>>| {
+            counts
+                .lock()
+                .unwrap()
+                .get(rule)
+                .copied()
+                .unwrap_or_default()
+        };
+        (count(&self.matched), count(&self.errors))
+    }
+}
+
+/// Counts requests into [`Stats`] as their access log events are emitted,
+/// whether or not the access log itself is written anywhere.
+pub struct Counter(pub Arc<Stats>);
+
+impl<S: Subscriber> Layer<S> for Counter {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if event.metadata().target() != logging::ACCESS {
+            return;
+        }
+        let mut fields = AccessFields::default();
+        event.record(&mut fields);
+        self.0.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(rule) = &fields.matched {
+            *self
+                .0
+                .matched
+                .lock()
+                .unwrap()
+                .entry(rule.clone())
+                .or_default() += 1;
+        }
+        if fields.status >= 500 {
+            let rule = fields.matched.unwrap_or_else(|| "(unmatched)".to_string());
+            *self.0.errors.lock().unwrap().entry(rule).or_default() += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct AccessFields {
+    matched: Option<String>,
+    status: u64,
+}
+
+impl Visit for AccessFields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "status" {
+            self.status = value;
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "matched" {
+            self.matched = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+    #[serde(flatten)]
+    labels: labels::Labels,
+    uptime_secs: u64,
+    requests: u64,
+    errors: BTreeMap<String, u64>,
+    connections_drained: usize,
+    connections_aborted: usize,
+}
+
+impl Report {
+    pub fn new(stats: &Stats, drained: usize, aborted: usize) -> Self {
+        Report {
+            labels: labels::get().clone(),
+            uptime_secs: stats.started.elapsed().as_secs(),
+            requests: stats.requests.load(Ordering::Relaxed),
+            errors: stats.errors.lock().unwrap().clone(),
+            connections_drained: drained,
+            connections_aborted: aborted,
+        }
+    }
+
+    /// Adds the counts of `other`, the report of a worker process.
+    pub fn merge(&mut self, other: &Report) {
+        self.requests += other.requests;
+        for (rule, errors) in &other.errors {
+            *self.errors.entry(rule.clone()).or_default() += errors;
+        }
+        self.connections_drained += other.connections_drained;
+        self.connections_aborted += other.connections_aborted;
+    }
+
+    /// Writes the report to the log and posts it as JSON to `webhook`.
+    pub async fn publish(&self, webhook: Option<&str>) {
+        tracing::info!(
+            uptime_secs = self.uptime_secs,
+            requests = self.requests,
+            errors = ?self.errors,
+            connections_drained = self.connections_drained,
+            connections_aborted = self.connections_aborted,
+            "shutdown report"
+        );
+        let Some(webhook) = webhook else {
+            return;
+        };
+        let sent = reqwest::Client::new()
+            .post(webhook)
+            .timeout(Duration::from_secs(10))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(self).unwrap_or_default())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = sent {
+            tracing::warn!(webhook, error = ?err, "shutdown report not delivered");
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How a rule treats rewritten targets using plain http.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceHttps {
+    Reject,
+    Upgrade,
+}
+
+/// Checks that `template` can only produce http(s) targets, and only https
+/// when `force` rejects http. Schemes taken from capture groups can't be
+/// known up front and are checked per request instead. Unix socket targets
+/// never leave the host, so `force` doesn't apply to them.
+pub fn validate_template(template: &str, force: Option<ForceHttps>) -> anyhow::Result<()> {
+    let literal = template.split('$').next().unwrap_or_default();
+    if let Some(socket) = literal.strip_prefix("unix:") {
+        if socket.is_empty() || socket.starts_with(":/") {
+            anyhow::bail!("target `{template}` has no socket path");
+        }
+        return Ok(());
+    }
+    match literal.split_once("://") {
+        Some((scheme, _)) => match scheme.to_ascii_lowercase().as_str() {
+            "https" => Ok(()),
+            "http" if force == Some(ForceHttps::Reject) => {
+                anyhow::bail!("target `{template}` uses http while `force_https: reject` is set")
+            }
+            "http" => Ok(()),
+            other => anyhow::bail!("target `{template}` uses unsupported scheme `{other}`"),
+        },
+        None if literal.len() == template.len() => {
+            anyhow::bail!("target `{template}` has no scheme")
+        }
+        None => Ok(()),
+    }
+}
+
+/// Rewrites a plain http `url` to https.
+pub fn upgrade(url: &str) -> Option<String> {
+    url.get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| format!("https://{}", &url[7..]))
+}
+
+/// Raised when a rule requiring https would forward to a plain http target.
+#[derive(Debug)]
+pub struct InsecureTarget(pub String);
+
+impl fmt::Display for InsecureTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` is not an https target", self.0)
+    }
+}
+
+impl std::error::Error for InsecureTarget {}
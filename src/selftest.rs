@@ -0,0 +1,117 @@
+use axum::{http::Method, routing::any, Router};
+use futures_util::StreamExt;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{parse_config, Config};
+
+/// Rules in the generated config, each matched by a share of the requests.
+const RULES: usize = 50;
+
+/// Routes `requests` synthetic requests, `concurrency` at a time, through a
+/// generated config to an embedded upstream and prints the latency of each
+/// stage: matching the rule, rewriting the url, and forwarding the request.
+pub async fn run(requests: usize, concurrency: usize) -> anyhow::Result<()> {
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let address = upstream.local_addr()?;
+    let app = Router::new().route("/*_", any(|| async { "ok" }));
+    tokio::spawn(axum::Server::from_tcp(upstream)?.serve(app.into_make_service()));
+
+    let table = parse_config(&config(address)?)?;
+    let started = Instant::now();
+    let samples: Vec<_> = futures_util::stream::iter(0..requests)
+        .map(|index| {
+            let table = &table;
+            async move {
+                let url = format!("selftest.local/svc{}/items/{index}", index % RULES);
+                let start = Instant::now();
+                let item = table
+                    .find(
+                        &url,
+                        "selftest.local",
+                        &Default::default(),
+                        Ipv4Addr::LOCALHOST.into(),
+                    )
+                    .ok_or_else(|| anyhow::anyhow!("no rule matched `{url}`"))?;
+                let matched = Instant::now();
+                let target_url = item.target_url(&url, &item.targets[0]);
+                let rewritten = Instant::now();
+                let request = reqwest::Request::new(Method::GET, target_url.parse()?);
+                item.client
+                    .get()
+                    .execute(request)
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await?;
+                let forwarded = Instant::now();
+                anyhow::Ok([matched - start, rewritten - matched, forwarded - rewritten])
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    let elapsed = started.elapsed();
+
+    let mut stages = [Vec::new(), Vec::new(), Vec::new()];
+    let mut errors = 0;
+    let mut first_error = None;
+    for sample in samples {
+        match sample {
+            Ok(sample) => {
+                for (stage, duration) in stages.iter_mut().zip(sample) {
+                    stage.push(duration);
+                }
+            }
+            Err(err) => {
+                errors += 1;
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+    println!(
+        "{requests} requests over {RULES} rules, {concurrency} concurrent, in {elapsed:.2?} ({:.0} req/s), {errors} failed",
+        requests as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10}",
+        "stage", "p50", "p90", "p99", "max"
+    );
+    for (name, stage) in ["match", "rewrite", "forward"]
+        .iter()
+        .zip(stages.iter_mut())
+    {
+        stage.sort();
+        println!(
+            "{name:<8} {:>10} {:>10} {:>10} {:>10}",
+            format!("{:.1?}", percentile(stage, 50)),
+            format!("{:.1?}", percentile(stage, 90)),
+            format!("{:.1?}", percentile(stage, 99)),
+            format!("{:.1?}", stage.last().copied().unwrap_or_default()),
+        );
+    }
+    match first_error {
+        Some(err) => Err(err.context(format!("{errors} selftest request(s) failed"))),
+        None => Ok(()),
+    }
+}
+
+fn config(upstream: SocketAddr) -> anyhow::Result<Config> {
+    let mut yaml = String::from("block_internal_targets: false\n");
+    for rule in 0..RULES {
+        yaml += &format!(
+            "svc{rule}:\n  match: \"^selftest\\\\.local/svc{rule}/(.*)\"\n  target: \"http://{upstream}/$1\"\n"
+        );
+    }
+    Ok(serde_yaml::from_str(&yaml)?)
+}
+
+/// The `percent`th percentile of sorted `durations`.
+fn percentile(durations: &[Duration], percent: usize) -> Duration {
+    if durations.is_empty() {
+        return Duration::ZERO;
+    }
+    durations[(durations.len() - 1) * percent / 100]
+}
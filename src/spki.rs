@@ -0,0 +1,109 @@
+use base64::Engine;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, RootCertStore, ServerName,
+};
+use std::time::SystemTime;
+
+/// Accepts upstream certificates that pass the usual web PKI checks and whose
+/// chain contains a public key from the pin set, so a certificate issued by a
+/// compromised CA is still refused.
+pub struct PinnedVerifier {
+    webpki: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| subject_public_key_info(&cert.0))
+            .any(|spki| {
+                let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+                self.pins.iter().any(|pin| pin == hash.as_ref())
+            });
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "no certificate in the chain matches a pinned public key".to_string(),
+            ))
+        }
+    }
+}
+
+/// Checks upstream certificates against `roots` and `pins`, each a base64
+/// SHA-256 hash of a DER encoded SubjectPublicKeyInfo written as
+/// `sha256/<hash>`.
+pub fn pinned_verifier(pins: &[String], roots: RootCertStore) -> anyhow::Result<PinnedVerifier> {
+    let pins = pins
+        .iter()
+        .map(|pin| {
+            let hash = pin
+                .strip_prefix("sha256/")
+                .ok_or_else(|| anyhow::anyhow!("pin `{pin}` must start with `sha256/`"))?;
+            let hash = base64::engine::general_purpose::STANDARD
+                .decode(hash)
+                .map_err(|err| anyhow::anyhow!("pin `{pin}` is not valid base64: {err}"))?;
+            <[u8; 32]>::try_from(hash)
+                .map_err(|_| anyhow::anyhow!("pin `{pin}` is not a SHA-256 hash"))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(PinnedVerifier {
+        webpki: WebPkiVerifier::new(roots, None),
+        pins,
+    })
+}
+
+/// Splits a DER element into its tag and content and what follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || input.len() < octets {
+            return None;
+        }
+        let len = input[..octets]
+            .iter()
+            .fold(0usize, |len, &byte| len << 8 | byte as usize);
+        input = &input[octets..];
+        len
+    };
+    (input.len() >= len).then(|| (tag, &input[..len], &input[len..]))
+}
+
+/// The encoded SubjectPublicKeyInfo of an X.509 certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs, _) = der_element(cert)?;
+    let mut rest = tbs;
+    // skip the explicitly tagged version if present
+    if rest.first() == Some(&0xa0) {
+        rest = der_element(rest)?.2;
+    }
+    // serial number, signature algorithm, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+    let (_, _, after) = der_element(rest)?;
+    Some(&rest[..rest.len() - after.len()])
+}
@@ -0,0 +1,160 @@
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Whether `ip` lies in a loopback, private, link-local (including cloud
+/// metadata endpoints), shared, multicast or otherwise non-public range.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_internal_v4(ip),
+            None => is_internal_v6(ip),
+        },
+    }
+}
+
+/// The IPv4 address carried by an IPv4-mapped (`::ffff:a.b.c.d`),
+/// IPv4-compatible (`::a.b.c.d`) or NAT64 (`64:ff9b::a.b.c.d`) address.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => {
+            Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+        }
+        _ => ip.to_ipv4(),
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// Raised when a target host only resolves to internal addresses.
+#[derive(Debug)]
+pub struct InternalTarget(pub String);
+
+impl fmt::Display for InternalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` resolves to an internal address", self.0)
+    }
+}
+
+impl std::error::Error for InternalTarget {}
+
+/// Whether the host of `url` is an internal IP literal, which never passes
+/// through the resolver.
+pub fn is_internal_literal(url: &reqwest::Url) -> bool {
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_internal(ip.into()),
+        Some(url::Host::Ipv6(ip)) => is_internal(ip.into()),
+        _ => false,
+    }
+}
+
+/// A resolver that drops internal addresses, failing when nothing public is
+/// left. Filtering at resolution time also covers redirects and leaves no gap
+/// between checking an address and connecting to it.
+pub struct PublicOnlyResolver;
+
+impl Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| !is_internal(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(InternalTarget(name.as_str().to_string()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn internal(ip: &str) -> bool {
+        is_internal(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn flags_internal_v4_ranges() {
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(internal(ip), "{ip}");
+        }
+        for ip in ["1.1.1.1", "8.8.8.8", "100.128.0.1", "172.32.0.1"] {
+            assert!(!internal(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn flags_internal_v6_ranges() {
+        for ip in ["::", "::1", "fc00::1", "fd12::1", "fe80::1", "ff02::1"] {
+            assert!(internal(ip), "{ip}");
+        }
+        assert!(!internal("2606:4700:4700::1111"));
+    }
+
+    #[test]
+    fn checks_v4_embedded_in_v6() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(internal(ip), "{ip}");
+        }
+        for ip in ["::ffff:8.8.8.8", "::8.8.8.8", "64:ff9b::8.8.8.8"] {
+            assert!(!internal(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn checks_literal_hosts() {
+        let url = |url: &str| reqwest::Url::parse(url).unwrap();
+        assert!(is_internal_literal(&url("http://[64:ff9b::a00:1]/")));
+        assert!(is_internal_literal(&url("http://127.0.0.1:8080/")));
+        assert!(!is_internal_literal(&url("http://localhost/")));
+        assert!(!is_internal_literal(&url("http://8.8.8.8/")));
+    }
+}
@@ -0,0 +1,280 @@
+use std::time::Duration;
+
+use crate::{report, stop_signal};
+
+/// Set in the environment of worker processes to their index.
+const WORKER_ENV: &str = "REPROXY_WORKER";
+/// Descriptor over which a worker sends its stats to the supervisor.
+const STATS_FD_ENV: &str = "REPROXY_STATS_FD";
+
+/// Interval of the stats snapshots workers send, so the requests of a worker
+/// that crashes still count towards the shutdown report.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A worker that exits sooner than this after starting is restarted only after
+/// this delay, so one that can't start doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Snapshot {
+    pid: u32,
+    report: report::Report,
+}
+
+/// The connection of a worker process to its supervisor.
+pub struct Worker {
+    pub index: usize,
+    #[cfg(unix)]
+    stats: std::os::unix::net::UnixDatagram,
+}
+
+impl Worker {
+    /// The worker this process was started as, if any.
+    #[cfg(unix)]
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        use std::os::fd::FromRawFd;
+
+        let Ok(index) = std::env::var(WORKER_ENV) else {
+            return Ok(None);
+        };
+        let fd = std::env::var(STATS_FD_ENV)?.parse()?;
+        let stats = unsafe { std::os::unix::net::UnixDatagram::from_raw_fd(fd) };
+        stats.set_nonblocking(true)?;
+        Ok(Some(Worker {
+            index: index.parse()?,
+            stats,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Sends `report` to the supervisor, dropping it if the supervisor is
+    /// behind.
+    pub fn send(&self, report: report::Report) {
+        #[cfg(unix)]
+        {
+            let snapshot = Snapshot {
+                pid: std::process::id(),
+                report,
+            };
+            let _ = self.stats.send(&serde_json::to_vec(&snapshot).unwrap());
+        }
+        #[cfg(not(unix))]
+        let _ = report;
+    }
+
+    /// Sends the stats of the process every [`SNAPSHOT_INTERVAL`] until the
+    /// returned task is aborted.
+    pub fn spawn_snapshots(
+        self: &std::sync::Arc<Self>,
+        stats: std::sync::Arc<report::Stats>,
+    ) -> tokio::task::JoinHandle<()> {
+        let worker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+            loop {
+                interval.tick().await;
+                worker.send(report::Report::new(&stats, 0, 0));
+            }
+        })
+    }
+}
+
+/// Binds a listener with `SO_REUSEPORT`, so that every worker has its own
+/// on the same port and the kernel spreads connections among them.
+#[cfg(unix)]
+pub fn bind_shared(address: &str) -> anyhow::Result<std::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+    use std::net::ToSocketAddrs;
+
+    let mut last_err = None;
+    for addr in address.to_socket_addrs()? {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        if let Err(err) = socket.bind(&addr.into()) {
+            last_err = Some(err);
+            continue;
+        }
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        return Ok(socket.into());
+    }
+    Err(match last_err {
+        Some(err) => err.into(),
+        None => anyhow::anyhow!("`{address}` resolves to no address"),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn bind_shared(_address: &str) -> anyhow::Result<std::net::TcpListener> {
+    anyhow::bail!("--workers is only supported on unix")
+}
+
+/// Runs `count` copies of this process as workers, restarting those that
+/// exit, until SIGTERM or Ctrl-C. Workers are then stopped with SIGTERM and
+/// their stats are published as one shutdown report. SIGHUP is passed on to
+/// every worker to reload its config, when they have a `config` file.
+#[cfg(unix)]
+pub async fn run(
+    count: usize,
+    stats: std::sync::Arc<report::Stats>,
+    webhook: Option<&str>,
+    config: Option<&str>,
+) -> anyhow::Result<()> {
+    use std::{collections::HashMap, os::fd::AsRawFd};
+    use tokio::{sync::watch, task::JoinSet};
+
+    let (receiver, sender) = std::os::unix::net::UnixDatagram::pair()?;
+    receiver.set_nonblocking(true)?;
+    let receiver = tokio::net::UnixDatagram::from_std(receiver)?;
+    let (stop, stopped) = watch::channel(false);
+    let (reload, reloads) = watch::channel(());
+    let mut workers = JoinSet::new();
+    for index in 0..count {
+        workers.spawn(keep_running(
+            index,
+            sender.as_raw_fd(),
+            stopped.clone(),
+            reloads.clone(),
+        ));
+    }
+
+    // the latest snapshot of every worker process, including exited ones
+    let mut snapshots = HashMap::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut record = |datagram: &[u8]| {
+        if let Ok(snapshot) = serde_json::from_slice::<Snapshot>(datagram) {
+            snapshots.insert(snapshot.pid, snapshot.report);
+        }
+    };
+    let signal = stop_signal();
+    tokio::pin!(signal);
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            result = &mut signal => {
+                result?;
+                break;
+            }
+            Some(()) = hangups.recv() => match config {
+                Some(path) => {
+                    tracing::info!(workers = count, path, "reloading workers");
+                    reload.send_replace(());
+                }
+                // inline rules have no file to reload from
+                None => tracing::warn!("no config file to reload"),
+            },
+            Ok(len) = receiver.recv(&mut buf) => record(&buf[..len]),
+        }
+    }
+
+    tracing::info!(workers = count, "stopping workers");
+    stop.send_replace(true);
+    loop {
+        tokio::select! {
+            joined = workers.join_next() => if joined.is_none() {
+                break;
+            },
+            Ok(len) = receiver.recv(&mut buf) => record(&buf[..len]),
+        }
+    }
+    // workers send their final report before they exit
+    while let Ok(len) = receiver.try_recv(&mut buf) {
+        record(&buf[..len]);
+    }
+
+    let mut report = report::Report::new(&stats, 0, 0);
+    for snapshot in snapshots.values() {
+        report.merge(snapshot);
+    }
+    report.publish(webhook).await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn run(
+    _count: usize,
+    _stats: std::sync::Arc<report::Stats>,
+    _webhook: Option<&str>,
+    _config: Option<&str>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--workers is only supported on unix")
+}
+
+/// Keeps worker `index` running until `stopped` changes, sending it SIGHUP
+/// whenever `reloads` changes.
+#[cfg(unix)]
+async fn keep_running(
+    index: usize,
+    stats_fd: std::os::fd::RawFd,
+    mut stopped: tokio::sync::watch::Receiver<bool>,
+    mut reloads: tokio::sync::watch::Receiver<()>,
+) {
+    use std::os::unix::process::ExitStatusExt;
+
+    loop {
+        let started = std::time::Instant::now();
+        let status = match spawn(index, stats_fd) {
+            Ok(mut child) => {
+                let pid = child.id().unwrap_or_default();
+                tracing::info!(worker = index, pid, "worker started");
+                loop {
+                    tokio::select! {
+                        status = child.wait() => break status.map(|status| (pid, status)),
+                        _ = stopped.changed() => {
+                            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                            let _ = child.wait().await;
+                            return;
+                        }
+                        Ok(()) = reloads.changed() => {
+                            unsafe { libc::kill(pid as libc::pid_t, libc::SIGHUP) };
+                        }
+                    }
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match status {
+            Ok((pid, status)) => tracing::warn!(
+                worker = index,
+                pid,
+                code = status.code(),
+                signal = status.signal(),
+                "worker exited, restarting"
+            ),
+            Err(err) => tracing::error!(worker = index, error = ?err, "worker failed to start"),
+        }
+        if let Some(delay) = RESTART_DELAY.checked_sub(started.elapsed()) {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = stopped.changed() => return,
+            }
+        }
+    }
+}
+
+/// Starts this executable again with the same arguments as worker `index`.
+#[cfg(unix)]
+fn spawn(index: usize, stats_fd: std::os::fd::RawFd) -> std::io::Result<tokio::process::Child> {
+    use std::os::unix::process::CommandExt;
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(WORKER_ENV, index.to_string())
+        .env(STATS_FD_ENV, stats_fd.to_string());
+    // the descriptor is close-on-exec in the supervisor
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(stats_fd, libc::F_SETFD, 0) {
+            -1 => Err(std::io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    tokio::process::Command::from(command)
+        .kill_on_drop(true)
+        .spawn()
+}
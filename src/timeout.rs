@@ -0,0 +1,24 @@
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::time::Duration;
+
+use crate::errors;
+
+/// Fails `body` when the upstream sends nothing for `timeout`, so a backend
+/// hanging mid-response doesn't hold the request open.
+pub fn between_reads(
+    body: impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static,
+    timeout: Option<Duration>,
+) -> impl Stream<Item = anyhow::Result<Bytes>> + Send + 'static {
+    stream::unfold(Some(Box::pin(body)), move |body| async move {
+        let mut body = body?;
+        let Some(timeout) = timeout else {
+            let chunk = body.next().await?;
+            return Some((chunk, Some(body)));
+        };
+        match tokio::time::timeout(timeout, body.next()).await {
+            Ok(chunk) => Some((chunk?, Some(body))),
+            Err(_) => Some((Err(errors::ReadTimeout(timeout).into()), None)),
+        }
+    })
+}
@@ -0,0 +1,104 @@
+use rustls::{
+    client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    Certificate, DigitallySignedStruct, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+    ServerName,
+};
+use std::{fs::File, io::BufReader, sync::Arc, time::SystemTime};
+use tokio_rustls::TlsAcceptor;
+
+fn open(path: &str) -> anyhow::Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| anyhow::anyhow!("can't open `{path}`: {err}"))
+}
+
+/// Reads the certificates of a PEM file, failing if there are none.
+pub fn read_certs(path: &str) -> anyhow::Result<Vec<Certificate>> {
+    let certs: Vec<_> = rustls_pemfile::certs(&mut open(path)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in `{path}`");
+    }
+    Ok(certs)
+}
+
+/// Loads a PEM certificate chain and private key for the HTTPS listener.
+pub fn acceptor(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let mut config = server_config(cert_path, key_path)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// A server config for the PEM certificate chain and private key, without
+/// ALPN protocols.
+pub fn server_config(cert_path: &str, key_path: &str) -> anyhow::Result<ServerConfig> {
+    let certs = read_certs(cert_path)?;
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key found in `{key_path}`"))?;
+
+    Ok(ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// The public web PKI roots upstream certificates are checked against, plus
+/// the `extra` ones of a rule's `ca_bundle`.
+pub fn upstream_roots(extra: &[Certificate]) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    for root in extra {
+        roots.add(root)?;
+    }
+    Ok(roots)
+}
+
+/// Accepts any upstream certificate, for `insecure_skip_verify`.
+pub struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &Certificate,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
@@ -0,0 +1,346 @@
+use axum::http::{header, HeaderValue};
+use std::{borrow::Cow, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::upstream;
+
+/// Splits a target `unix:/var/run/app.sock:/path` into the socket and the
+/// url requested over it, `http://localhost/path`. Other targets are
+/// returned as they are.
+pub fn split(target: &str) -> (Option<PathBuf>, Cow<'_, str>) {
+    let Some(rest) = target.strip_prefix("unix:") else {
+        return (None, target.into());
+    };
+    let (socket, path) = match rest.find(":/") {
+        Some(index) => (&rest[..index], &rest[index + 1..]),
+        None => (rest, "/"),
+    };
+    (
+        Some(PathBuf::from(socket)),
+        format!("http://localhost{path}").into(),
+    )
+}
+
+/// The client of a rule for its targets on Unix sockets, keeping a pool of
+/// connections per socket. Requests are sent with the `Settings` of the
+/// rule's TCP client, only redirects are handed to the client.
+pub struct UnixClient {
+    settings: Arc<upstream::Settings>,
+    connect_timeout: Option<Duration>,
+    #[cfg(unix)]
+    pools: std::sync::Mutex<
+        std::collections::HashMap<PathBuf, upstream::Client<connector::Connector>>,
+    >,
+}
+
+impl UnixClient {
+    pub fn new(settings: &upstream::Settings, connect_timeout: Option<Duration>) -> Self {
+        UnixClient {
+            settings: Arc::new(upstream::Settings {
+                redirect: None,
+                ..settings.clone()
+            }),
+            connect_timeout,
+            #[cfg(unix)]
+            pools: Default::default(),
+        }
+    }
+
+    /// Sends `request` to the Unix socket `socket`. Its Host header is the
+    /// client's with `preserve_host`, `localhost` otherwise.
+    #[cfg(unix)]
+    pub async fn send(
+        &self,
+        socket: &std::path::Path,
+        mut request: reqwest::Request,
+    ) -> anyhow::Result<reqwest::Response> {
+        let client = self
+            .pools
+            .lock()
+            .unwrap()
+            .entry(socket.to_path_buf())
+            .or_insert_with(|| {
+                let connector = connector::Connector::new(socket, self.connect_timeout);
+                upstream::Client::new(connector, self.settings.clone())
+            })
+            .clone();
+
+        if self.settings.http2_only {
+            // HTTP/2 carries the host as the authority of the uri
+            let host = request
+                .headers_mut()
+                .remove(header::HOST)
+                .unwrap_or(HeaderValue::from_static("localhost"));
+            let path = request.url()[url::Position::BeforePath..].to_string();
+            *request.url_mut() = format!("http://{}{path}", host.to_str()?).parse()?;
+        }
+        client.execute(request).await
+    }
+
+    #[cfg(not(unix))]
+    pub async fn send(
+        &self,
+        _socket: &std::path::Path,
+        _request: reqwest::Request,
+    ) -> anyhow::Result<reqwest::Response> {
+        anyhow::bail!("unix socket targets are only supported on unix")
+    }
+}
+
+#[cfg(unix)]
+mod connector {
+    use hyper::{
+        client::connect::{Connected, Connection},
+        Uri,
+    };
+    use std::{
+        future::Future,
+        io,
+        path::Path,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        net::UnixStream,
+    };
+
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    /// Connects to one Unix socket whatever the uri.
+    #[derive(Clone)]
+    pub struct Connector {
+        socket: Arc<Path>,
+        timeout: Option<Duration>,
+    }
+
+    impl Connector {
+        pub fn new(socket: &Path, timeout: Option<Duration>) -> Self {
+            Connector {
+                socket: socket.into(),
+                timeout,
+            }
+        }
+    }
+
+    impl tower::Service<Uri> for Connector {
+        type Response = Stream;
+        type Error = Error;
+        type Future = Pin<Box<dyn Future<Output = Result<Stream, Error>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            let (socket, timeout) = (self.socket.clone(), self.timeout);
+            Box::pin(async move {
+                let connect = UnixStream::connect(&socket);
+                let connected = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, connect)
+                        .await
+                        .map_err(|_| crate::errors::ConnectTimeout(timeout))?,
+                    None => connect.await,
+                };
+                let stream = connected
+                    .map_err(|err| format!("connecting to `{}`: {err}", socket.display()))?;
+                crate::timing::connected();
+                Ok(Stream(stream))
+            })
+        }
+    }
+
+    /// A connection to a Unix socket, as hyper's pool takes it.
+    pub struct Stream(UnixStream);
+
+    impl Connection for Stream {
+        fn connected(&self) -> Connected {
+            Connected::new()
+        }
+    }
+
+    impl AsyncRead for Stream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Stream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use axum::http;
+    use hyper::{server::conn::Http, service::service_fn, Body, Response, StatusCode};
+    use std::{
+        convert::Infallible,
+        path::Path,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixListener,
+    };
+
+    /// Serves on a fresh socket, answering `/slow` never, upgrade requests
+    /// with an echoing tunnel and anything else with what it received.
+    /// Returns the socket and the count of accepted connections.
+    fn serve(name: &str) -> (PathBuf, Arc<AtomicUsize>) {
+        let socket =
+            std::env::temp_dir().join(format!("reproxy-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let service = service_fn(|mut request: http::Request<Body>| async move {
+                    if request.uri().path() == "/slow" {
+                        std::future::pending::<()>().await;
+                    }
+                    if request.headers().contains_key(header::UPGRADE) {
+                        let upgrade = hyper::upgrade::on(&mut request);
+                        tokio::spawn(async move {
+                            let mut upgraded = upgrade.await.unwrap();
+                            let mut buf = [0; 4];
+                            upgraded.read_exact(&mut buf).await.unwrap();
+                            upgraded.write_all(&buf).await.unwrap();
+                        });
+                        return Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(StatusCode::SWITCHING_PROTOCOLS)
+                                .header(header::CONNECTION, "upgrade")
+                                .header(header::UPGRADE, "echo")
+                                .body(Body::empty())
+                                .unwrap(),
+                        );
+                    }
+                    let header = |name| {
+                        request
+                            .headers()
+                            .get(name)
+                            .map_or("-", |value: &HeaderValue| value.to_str().unwrap())
+                            .to_string()
+                    };
+                    let echo = format!(
+                        "{} host={} ua={}",
+                        request.uri(),
+                        header(header::HOST),
+                        header(header::USER_AGENT)
+                    );
+                    Ok(Response::new(Body::from(echo)))
+                });
+                tokio::spawn(
+                    Http::new()
+                        .serve_connection(stream, service)
+                        .with_upgrades(),
+                );
+            }
+        });
+        (socket, accepted)
+    }
+
+    fn request(url: &str) -> reqwest::Request {
+        reqwest::Client::new().get(url).build().unwrap()
+    }
+
+    async fn text(client: &UnixClient, socket: &Path, url: &str) -> String {
+        let response = client.send(socket, request(url)).await.unwrap();
+        response.text().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxies_requests_over_a_pooled_connection() {
+        let (socket, accepted) = serve("pool");
+        let settings = upstream::Settings {
+            user_agent: Some(HeaderValue::from_static("reproxy-test")),
+            ..Default::default()
+        };
+        let client = UnixClient::new(&settings, None);
+        assert_eq!(
+            text(&client, &socket, "http://localhost/a?b=1").await,
+            "/a?b=1 host=localhost ua=reproxy-test"
+        );
+        let mut preserved = request("http://localhost/c");
+        preserved
+            .headers_mut()
+            .insert(header::HOST, HeaderValue::from_static("example.com"));
+        preserved
+            .headers_mut()
+            .insert(header::USER_AGENT, HeaderValue::from_static("curl"));
+        let response = client.send(&socket, preserved).await.unwrap();
+        assert_eq!(
+            response.text().await.unwrap(),
+            "/c host=example.com ua=curl"
+        );
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        let _ = std::fs::remove_file(socket);
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_upstreams() {
+        let (socket, _) = serve("slow");
+        let settings = upstream::Settings {
+            request_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let client = UnixClient::new(&settings, None);
+        let err = client
+            .send(&socket, request("http://localhost/slow"))
+            .await
+            .unwrap_err();
+        assert!(err.is::<crate::errors::RequestTimeout>(), "{err:?}");
+        assert_eq!(
+            text(&client, &socket, "http://localhost/fast").await,
+            "/fast host=localhost ua=-"
+        );
+        let _ = std::fs::remove_file(socket);
+    }
+
+    #[tokio::test]
+    async fn carries_upgrades() {
+        let (socket, _) = serve("upgrade");
+        let client = UnixClient::new(&Default::default(), None);
+        let mut upgrade = request("http://localhost/ws");
+        upgrade
+            .headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        upgrade
+            .headers_mut()
+            .insert(header::UPGRADE, HeaderValue::from_static("echo"));
+        let response = client.send(&socket, upgrade).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let mut upgraded = response.upgrade().await.unwrap();
+        upgraded.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        upgraded.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        let _ = std::fs::remove_file(socket);
+    }
+}
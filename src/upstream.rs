@@ -0,0 +1,188 @@
+use axum::http::{self, header, HeaderMap, HeaderValue, Method, StatusCode};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use hyper::client::connect::Connect;
+use reqwest::Url;
+use std::{sync::Arc, time::Duration};
+
+use crate::{errors, redirect::RedirectRules};
+
+/// How a rule's client sends its requests, over whichever connector.
+#[derive(Clone, Default)]
+pub struct Settings {
+    /// added to requests without a User-Agent
+    pub user_agent: Option<HeaderValue>,
+    /// runs until the end of the response body
+    pub request_timeout: Option<Duration>,
+    pub pool_idle_timeout: Option<Duration>,
+    /// speaks HTTP/2 without negotiating it
+    pub http2_only: bool,
+    /// redirects are handed to the client without
+    pub redirect: Option<RedirectRules>,
+}
+
+/// A pool of the connections made by `C`, sending requests the way the
+/// rule's `Settings` ask.
+#[derive(Clone)]
+pub struct Client<C> {
+    inner: hyper::Client<C>,
+    settings: Arc<Settings>,
+}
+
+impl<C: Connect + Clone + Send + Sync + 'static> Client<C> {
+    pub fn new(connector: C, settings: Arc<Settings>) -> Self {
+        let mut client = hyper::Client::builder();
+        client.http2_only(settings.http2_only);
+        if let Some(timeout) = settings.pool_idle_timeout {
+            client.pool_idle_timeout(timeout);
+        }
+        Client {
+            inner: client.build(connector),
+            settings,
+        }
+    }
+
+    pub async fn execute(&self, request: reqwest::Request) -> anyhow::Result<reqwest::Response> {
+        let Some(timeout) = self.settings.request_timeout else {
+            return Ok(self.follow(request).await?.into());
+        };
+        let deadline = tokio::time::Instant::now() + timeout;
+        let response = tokio::time::timeout_at(deadline, self.follow(request))
+            .await
+            .map_err(|_| errors::RequestTimeout(timeout))??;
+        Ok(response
+            .map(|body| hyper::Body::wrap_stream(until_deadline(body, deadline, timeout)))
+            .into())
+    }
+
+    /// Sends `request`, following the redirects the rule allows like reqwest
+    /// would: 301 to 303 turn into a GET without a body, and 307 and 308 are
+    /// only followed if the body can be sent again.
+    async fn follow(
+        &self,
+        mut request: reqwest::Request,
+    ) -> anyhow::Result<http::Response<hyper::Body>> {
+        let Some(rules) = &self.settings.redirect else {
+            return Ok(self.inner.request(self.convert(request)?).await?);
+        };
+        let mut previous = Vec::new();
+        loop {
+            let url = request.url().clone();
+            let method = request.method().clone();
+            let mut headers = request.headers().clone();
+            // streamed bodies can't be sent again
+            let body = request
+                .body()
+                .map(|body| body.as_bytes().map(Bytes::copy_from_slice));
+            let response = self.inner.request(self.convert(request)?).await?;
+
+            let (method, body) = match response.status() {
+                StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER => {
+                    for name in [
+                        header::TRANSFER_ENCODING,
+                        header::CONTENT_ENCODING,
+                        header::CONTENT_TYPE,
+                        header::CONTENT_LENGTH,
+                    ] {
+                        headers.remove(name);
+                    }
+                    match method {
+                        Method::GET | Method::HEAD => (method, None),
+                        _ => (Method::GET, None),
+                    }
+                }
+                StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => match body {
+                    Some(None) => return Ok(response),
+                    body => (method, body.flatten()),
+                },
+                _ => return Ok(response),
+            };
+            let next = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| url.join(location.to_str().ok()?).ok());
+            let Some(next) = next else {
+                return Ok(response);
+            };
+            previous.push(url);
+            if !rules.follows(&next, &previous)? {
+                return Ok(response);
+            }
+
+            let url = &previous[previous.len() - 1];
+            if next.host_str() != url.host_str()
+                || next.port_or_known_default() != url.port_or_known_default()
+            {
+                remove_credentials(&mut headers);
+            }
+            match referer(&next, url) {
+                Some(referer) => headers.insert(header::REFERER, referer),
+                None => headers.remove(header::REFERER),
+            };
+            request = reqwest::Request::new(method, next);
+            *request.headers_mut() = headers;
+            *request.body_mut() = body.map(Into::into);
+        }
+    }
+
+    fn convert(&self, request: reqwest::Request) -> anyhow::Result<http::Request<hyper::Body>> {
+        let mut request = http::Request::<reqwest::Body>::try_from(request)?;
+        if let Some(user_agent) = &self.settings.user_agent {
+            request
+                .headers_mut()
+                .entry(header::USER_AGENT)
+                .or_insert_with(|| user_agent.clone());
+        }
+        Ok(request.map(|body| match body.as_bytes() {
+            // sent with its length
+            Some(bytes) => Bytes::copy_from_slice(bytes).into(),
+            // reqwest only exposes the stream of a body on its responses
+            None => hyper::Body::wrap_stream(
+                reqwest::Response::from(http::Response::new(body)).bytes_stream(),
+            ),
+        }))
+    }
+}
+
+/// Drops the headers reqwest keeps from another host or port.
+fn remove_credentials(headers: &mut HeaderMap) {
+    for name in [
+        header::AUTHORIZATION,
+        header::COOKIE,
+        header::HeaderName::from_static("cookie2"),
+        header::PROXY_AUTHORIZATION,
+        header::WWW_AUTHENTICATE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+/// The Referer of a redirect from `previous` to `next`, unless it would
+/// leave https for http.
+fn referer(next: &Url, previous: &Url) -> Option<HeaderValue> {
+    if next.scheme() == "http" && previous.scheme() == "https" {
+        return None;
+    }
+    let mut referer = previous.clone();
+    let _ = referer.set_username("");
+    let _ = referer.set_password(None);
+    referer.set_fragment(None);
+    referer.as_str().parse().ok()
+}
+
+/// Fails `body` with `RequestTimeout` if it hasn't ended by `deadline`.
+fn until_deadline(
+    body: hyper::Body,
+    deadline: tokio::time::Instant,
+    timeout: Duration,
+) -> impl Stream<Item = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>> + Send + 'static {
+    futures_util::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        tokio::select! {
+            chunk = body.next() => Some((chunk?.map_err(Into::into), Some(body))),
+            _ = tokio::time::sleep_until(deadline) => {
+                Some((Err(errors::RequestTimeout(timeout).into()), None))
+            }
+        }
+    })
+}
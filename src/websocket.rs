@@ -0,0 +1,47 @@
+use axum::http::{header, HeaderMap};
+use hyper::upgrade::OnUpgrade;
+use tokio::sync::watch;
+
+/// Request headers a WebSocket handshake needs upstream whatever the rule's
+/// header actions say.
+pub const HANDSHAKE_HEADERS: [header::HeaderName; 6] = [
+    header::CONNECTION,
+    header::UPGRADE,
+    header::SEC_WEBSOCKET_KEY,
+    header::SEC_WEBSOCKET_VERSION,
+    header::SEC_WEBSOCKET_PROTOCOL,
+    header::SEC_WEBSOCKET_EXTENSIONS,
+];
+
+/// Whether the request asks to upgrade the connection to a WebSocket.
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    let has_token = |name, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+/// Once the client has received the upstream's 101 response, relays bytes
+/// both ways until either side closes or the target is cut off.
+pub fn tunnel(client: OnUpgrade, upstream: reqwest::Response, mut cutoff: watch::Receiver<bool>) {
+    tokio::spawn(async move {
+        let relay = async {
+            let mut upstream = upstream.upgrade().await?;
+            let mut client = client.await?;
+            tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+            anyhow::Ok(())
+        };
+        let result = tokio::select! {
+            result = relay => result,
+            _ = cutoff.wait_for(|retired| *retired) => Err(anyhow::anyhow!("target drained")),
+        };
+        if let Err(err) = result {
+            tracing::debug!(error = ?err, "websocket tunnel closed");
+        }
+    });
+}